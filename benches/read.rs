@@ -0,0 +1,181 @@
+//! Read path benchmarks.
+//!
+//! Every read is a single `recvmsg`, including reads that run into a control record.
+//! `Before` is the baseline read path: a plain `read` of the socket, which fails with
+//! `EIO` at a control record, then a `recvmsg` of that record into a 16 KiB buffer,
+//! then the `read` again.
+//!
+//! To count the syscalls each path makes, run one round of `ROUNDS` async reads under
+//! strace, with `KTLS_BENCH_ONCE` set to `before` or `after`:
+//!
+//! ```text
+//! KTLS_BENCH_ONCE=before strace -f -c -e trace=read,recvfrom,recvmsg,epoll_wait,epoll_pwait \
+//!     target/release/deps/read-<hash>
+//! ```
+//!
+//! or count `syscalls:sys_enter_*` events with `perf stat` the same way.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{ env, io, thread };
+use std::io::{ Read, Write };
+use std::net::{ TcpListener, TcpStream, SocketAddr };
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{ channel, Sender };
+use criterion::{ Criterion, criterion_group };
+use tokio::prelude::*;
+use tokio::io as aio;
+use tokio::runtime::current_thread;
+use ktls::sys;
+use ktls::codec::{ Level, AlertDescription };
+use tokio_rusktls::KtlsStream;
+use self::common::{ accept, connect, into_tokio };
+
+
+const ROUNDS: usize = 64;
+const PAYLOAD: &[u8] = b"ping";
+
+fn ktls_pair<IO, F>(f: F) -> (KtlsStream<TcpStream>, KtlsStream<IO>)
+where
+    IO: Read + Write + AsRawFd,
+    F: FnOnce(TcpStream) -> IO
+{
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let sess = accept(&mut sock);
+        (sock, sess)
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let client = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let (sock, sess) = server.join().unwrap();
+    let server = KtlsStream::new(f(sock), sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    (client, server)
+}
+
+/// The baseline read path, taking a control record aside with `recvmsg` when `read`
+/// fails with `EIO`. The bench only sends warning alerts, so they are not checked.
+struct Before<IO>(KtlsStream<IO>);
+
+impl<IO: Read + Write + AsRawFd> Read for Before<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let io = self.0.get_mut().get_mut();
+
+        match io.read(buf) {
+            Err(ref err) if err.raw_os_error() == Some(libc::EIO) => {
+                let mut record = [0; 16 * 1024];
+                unsafe { sys::recv_ctrl_message(io, &mut record)? };
+                self.read(buf)
+            },
+            result => result
+        }
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite + AsRawFd> AsyncRead for Before<IO> {
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
+        false
+    }
+}
+
+/// Read `ROUNDS` pings on this thread, each sent behind an alert once the last one
+/// was read, so that every round starts with a read that would block.
+fn ping_pong<R, F>(wrap: F)
+where
+    R: AsyncRead + 'static,
+    F: FnOnce(KtlsStream<tokio::net::TcpStream>) -> R
+{
+    let (mut client, server) = ktls_pair(into_tokio);
+    let server = wrap(server);
+    let (pong, recv_pong) = channel();
+
+    let client = thread::spawn(move || for _ in 0..ROUNDS {
+        client.send_alert(Level::Warning, AlertDescription::UserCanceled).unwrap();
+        client.write_record(PAYLOAD).unwrap();
+        recv_pong.recv().unwrap();
+    });
+
+    fn round<R: AsyncRead>(server: R, pong: Sender<()>, n: usize)
+        -> Box<dyn Future<Item = R, Error = io::Error>>
+    {
+        Box::new(aio::read_exact(server, [0; 4]).and_then(move |(server, buf)| {
+            assert_eq!(&buf, PAYLOAD);
+            pong.send(()).unwrap();
+
+            if n > 1 {
+                round(server, pong, n - 1)
+            } else {
+                Box::new(future::ok(server))
+            }
+        }))
+    }
+
+    current_thread::block_on_all(round(server, pong, ROUNDS)).unwrap();
+    client.join().unwrap();
+}
+
+fn bench_read(c: &mut Criterion) {
+    c.bench_function("read data", |b| {
+        let (mut client, mut server) = ktls_pair(|sock| sock);
+        let mut buf = [0; 64];
+
+        b.iter(|| for _ in 0..ROUNDS {
+            client.write_record(PAYLOAD).unwrap();
+            server.read_exact(&mut buf[..PAYLOAD.len()]).unwrap();
+        })
+    });
+
+    c.bench_function("read after alert", |b| {
+        let (mut client, mut server) = ktls_pair(|sock| sock);
+        let mut buf = [0; 64];
+
+        b.iter(|| for _ in 0..ROUNDS {
+            client.send_alert(Level::Warning, AlertDescription::UserCanceled).unwrap();
+            client.write_record(PAYLOAD).unwrap();
+            server.read_exact(&mut buf[..PAYLOAD.len()]).unwrap();
+        })
+    });
+
+    c.bench_function("read after alert, before", |b| {
+        let (mut client, server) = ktls_pair(|sock| sock);
+        let mut server = Before(server);
+        let mut buf = [0; 64];
+
+        b.iter(|| for _ in 0..ROUNDS {
+            client.send_alert(Level::Warning, AlertDescription::UserCanceled).unwrap();
+            client.write_record(PAYLOAD).unwrap();
+            server.read_exact(&mut buf[..PAYLOAD.len()]).unwrap();
+        })
+    });
+
+    c.bench_function("async read after alert, before", |b| b.iter(|| ping_pong(Before)));
+    c.bench_function("async read after alert, after", |b| b.iter(|| ping_pong(|stream| stream)));
+}
+
+criterion_group!(benches, bench_read);
+
+fn main() {
+    match env::var("KTLS_BENCH_ONCE").as_ref().map(String::as_str) {
+        Ok("before") => return ping_pong(Before),
+        Ok("after") => return ping_pong(|stream| stream),
+        Ok(name) => panic!("unknown read path: {}", name),
+        Err(_) => ()
+    }
+
+    benches();
+    Criterion::default()
+        .configure_from_args()
+        .final_summary();
+}
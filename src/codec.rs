@@ -2,18 +2,115 @@ use std::io;
 
 
 pub trait Record {
-    fn build(level: Level, typ: Alert) -> Vec<u8>;
-    fn check(buf: &[u8]) -> io::Result<Option<(Level, Alert)>>;
+    fn build(level: Level, typ: AlertDescription) -> Vec<u8>;
+    fn check(buf: &[u8]) -> io::Result<Option<(Level, AlertDescription)>>;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Level {
     Warning,
     Fatal
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum Alert {
-    CloseNotify,
-    Other(u8)
+macro_rules! u8_enum {
+    (
+        $( #[$attr:meta] )*
+        pub enum $name:ident {
+            $( $variant:ident => $val:expr ),* $(,)*
+        }
+    ) => {
+        $( #[$attr] )*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $( $variant, )*
+            Unknown(u8)
+        }
+
+        impl From<u8> for $name {
+            fn from(val: u8) -> $name {
+                match val {
+                    $( $val => $name::$variant, )*
+                    val => $name::Unknown(val)
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(val: $name) -> u8 {
+                match val {
+                    $( $name::$variant => $val, )*
+                    $name::Unknown(val) => val
+                }
+            }
+        }
+    }
+}
+
+u8_enum! {
+    pub enum ContentType {
+        ChangeCipherSpec => 0x14,
+        Alert => 0x15,
+        Handshake => 0x16,
+        ApplicationData => 0x17,
+    }
+}
+
+u8_enum! {
+    pub enum HandshakeType {
+        HelloRequest => 0,
+        ClientHello => 1,
+        ServerHello => 2,
+        NewSessionTicket => 4,
+        EndOfEarlyData => 5,
+        EncryptedExtensions => 8,
+        Certificate => 11,
+        ServerKeyExchange => 12,
+        CertificateRequest => 13,
+        ServerHelloDone => 14,
+        CertificateVerify => 15,
+        ClientKeyExchange => 16,
+        Finished => 20,
+        KeyUpdate => 24,
+        MessageHash => 254,
+    }
+}
+
+u8_enum! {
+    /// Alert descriptions from RFC 5246 and RFC 8446.
+    pub enum AlertDescription {
+        CloseNotify => 0,
+        UnexpectedMessage => 10,
+        BadRecordMac => 20,
+        DecryptionFailed => 21,
+        RecordOverflow => 22,
+        DecompressionFailure => 30,
+        HandshakeFailure => 40,
+        NoCertificate => 41,
+        BadCertificate => 42,
+        UnsupportedCertificate => 43,
+        CertificateRevoked => 44,
+        CertificateExpired => 45,
+        CertificateUnknown => 46,
+        IllegalParameter => 47,
+        UnknownCA => 48,
+        AccessDenied => 49,
+        DecodeError => 50,
+        DecryptError => 51,
+        ExportRestriction => 60,
+        ProtocolVersion => 70,
+        InsufficientSecurity => 71,
+        InternalError => 80,
+        InappropriateFallback => 86,
+        UserCanceled => 90,
+        NoRenegotiation => 100,
+        MissingExtension => 109,
+        UnsupportedExtension => 110,
+        CertificateUnobtainable => 111,
+        UnrecognisedName => 112,
+        BadCertificateStatusResponse => 113,
+        BadCertificateHashValue => 114,
+        UnknownPSKIdentity => 115,
+        CertificateRequired => 116,
+        NoApplicationProtocol => 120,
+    }
 }
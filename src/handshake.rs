@@ -0,0 +1,307 @@
+use std::{ io, fmt, mem };
+use std::os::unix::io::AsRawFd;
+use log::warn;
+use byteorder::{ ByteOrder, NetworkEndian };
+use crate::codec::{ Record, Level, AlertDescription, HandshakeType, ContentType };
+use crate::{ sys, KtlsStream, KtlsErrorKind, State, CryptoInfo };
+
+
+/// What to do when the peer tries to renegotiate a TLS 1.2 connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renegotiation {
+    /// Answer with a no_renegotiation warning and carry on.
+    Refuse,
+    /// Fail the stream with a fatal handshake_failure alert.
+    Fatal
+}
+
+impl Default for Renegotiation {
+    fn default() -> Renegotiation {
+        Renegotiation::Refuse
+    }
+}
+
+/// A TLS 1.3 NewSessionTicket received after install.
+///
+/// The kernel only hands us the ticket. The resumption secret it belongs to
+/// lives in the TLS library, so on its own one of these can not resume a session.
+/// Set a `Session` to pass tickets on to the library, as the `rustls` helpers
+/// `connect_kernel` and `accept_kernel` do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionTicket {
+    pub lifetime: u32,
+    pub age_add: u32,
+    pub nonce: Vec<u8>,
+    pub ticket: Vec<u8>,
+    /// Raw extensions, still encoded.
+    pub extensions: Vec<u8>
+}
+
+impl SessionTicket {
+    fn read(body: &[u8]) -> Option<SessionTicket> {
+        fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if buf.len() < n {
+                return None;
+            }
+
+            let (x, rest) = buf.split_at(n);
+            *buf = rest;
+            Some(x)
+        }
+
+        let mut buf = body;
+        let lifetime = NetworkEndian::read_u32(take(&mut buf, 4)?);
+        let age_add = NetworkEndian::read_u32(take(&mut buf, 4)?);
+        let len = take(&mut buf, 1)?[0] as usize;
+        let nonce = take(&mut buf, len)?.to_vec();
+        let len = NetworkEndian::read_u16(take(&mut buf, 2)?) as usize;
+        let ticket = take(&mut buf, len)?.to_vec();
+        let len = NetworkEndian::read_u16(take(&mut buf, 2)?) as usize;
+        let extensions = take(&mut buf, len)?.to_vec();
+
+        if ticket.is_empty() || !buf.is_empty() {
+            return None;
+        }
+
+        Some(SessionTicket { lifetime, age_add, nonce, ticket, extensions })
+    }
+}
+
+/// What the TLS library keeps of a connection once the kernel has its keys.
+///
+/// Some handshake messages received after install can only be handled with
+/// secrets the library holds, so they are passed on to it.
+pub trait Session: Send {
+    /// Take a TLS 1.3 NewSessionTicket, without its handshake header,
+    /// so later connections can resume with it.
+    fn handle_ticket(&mut self, body: &[u8]) -> io::Result<()>;
+
+    /// The next TLS 1.3 traffic key for sending, after a KeyUpdate of ours.
+    fn update_tx(&mut self) -> io::Result<CryptoInfo>;
+
+    /// The next TLS 1.3 traffic key for receiving, after a KeyUpdate from the peer.
+    fn update_rx(&mut self) -> io::Result<CryptoInfo>;
+}
+
+const UPDATE_NOT_REQUESTED: u8 = 0;
+const UPDATE_REQUESTED: u8 = 1;
+
+/// A KeyUpdate message, with its handshake header.
+fn key_update(request_update: bool) -> [u8; 5] {
+    let request = if request_update { UPDATE_REQUESTED } else { UPDATE_NOT_REQUESTED };
+    [HandshakeType::KeyUpdate.into(), 0, 0, 1, request]
+}
+
+pub(crate) struct SessionHandle(Option<Box<dyn Session>>);
+
+impl Default for SessionHandle {
+    fn default() -> SessionHandle {
+        SessionHandle(None)
+    }
+}
+
+impl fmt::Debug for SessionHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SessionHandle")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+pub(crate) struct TicketHandler(Option<Box<dyn FnMut(SessionTicket) + Send>>);
+
+impl Default for TicketHandler {
+    fn default() -> TicketHandler {
+        TicketHandler(None)
+    }
+}
+
+impl fmt::Debug for TicketHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("TicketHandler")
+            .field(&self.0.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl<IO, R> KtlsStream<IO, R> {
+    #[inline]
+    pub fn set_renegotiation(&mut self, renegotiation: Renegotiation) {
+        self.renegotiation = renegotiation;
+    }
+
+    /// Call `f` with every session ticket the server sends after install.
+    ///
+    /// Without a handler, tickets are only passed on to the `Session`, if there is one.
+    /// The tickets are raw, see `SessionTicket` for why they can not resume a session
+    /// on their own.
+    pub fn set_ticket_handler<F>(&mut self, f: F)
+    where F: FnMut(SessionTicket) + Send + 'static
+    {
+        self.ticket_handler = TicketHandler(Some(Box::new(f)));
+    }
+
+    /// Pass the handshake messages only the TLS library can handle on to `session`.
+    pub fn set_session<S>(&mut self, session: S)
+    where S: Session + 'static
+    {
+        self.session = SessionHandle(Some(Box::new(session)));
+    }
+}
+
+impl<IO: AsRawFd, R: Record> KtlsStream<IO, R> {
+    /// Handle the handshake messages in a record received after install.
+    ///
+    /// A message can span records, so whatever is left of one is kept for the next.
+    pub(crate) fn handle_handshake(&mut self, payload: &[u8]) -> io::Result<()> {
+        const HEADER_LENGTH: usize = 4;
+        // a NewSessionTicket with the longest ticket and extensions
+        const MAX_MESSAGE_LENGTH: usize = 4 + 4 + 1 + 0xff + 2 + 0xffff + 2 + 0xffff;
+
+        let mut pending = mem::replace(&mut self.pending_handshake, Vec::new());
+        pending.extend_from_slice(payload);
+
+        let mut offset = 0;
+        let result = loop {
+            let rest = &pending[offset..];
+
+            if rest.len() < HEADER_LENGTH {
+                break Ok(());
+            }
+
+            let len = NetworkEndian::read_u24(&rest[1..HEADER_LENGTH]) as usize;
+
+            if len > MAX_MESSAGE_LENGTH {
+                let _ = self.send_alert(Level::Fatal, AlertDescription::DecodeError);
+                let kind = KtlsErrorKind::AlertSent(AlertDescription::DecodeError);
+                self.state = State::Failed(kind);
+                break Err(kind.into_error());
+            }
+
+            let end = HEADER_LENGTH + len;
+
+            if rest.len() < end {
+                break Ok(());
+            }
+
+            if let Err(err) = self.handle_message(HandshakeType::from(rest[0]), &rest[HEADER_LENGTH..end]) {
+                break Err(err);
+            }
+
+            offset += end;
+        };
+
+        pending.drain(..offset);
+        self.pending_handshake = pending;
+        result
+    }
+
+    fn handle_message(&mut self, typ: HandshakeType, body: &[u8]) -> io::Result<()> {
+        match typ {
+            // a server asking us to start over, or a client starting over
+            HandshakeType::HelloRequest | HandshakeType::ClientHello => self.refuse_renegotiation(),
+            HandshakeType::NewSessionTicket => {
+                self.handle_ticket(body);
+                Ok(())
+            },
+            HandshakeType::KeyUpdate => self.handle_key_update(body),
+            _ => Ok(())
+        }
+    }
+
+    /// Move our side of a TLS 1.3 connection to a new traffic key and tell the peer,
+    /// as the confidentiality limit of the cipher suite asks for in time.
+    /// With `request_update`, the peer is asked to move to a new key as well.
+    ///
+    /// This needs a `Session` to derive the key, and fails with `Unsupported` without one.
+    /// The kernel takes new keys from Linux 6.13 on. If it does not, the KeyUpdate is
+    /// already out, so the stream fails with `KeyUpdate`.
+    pub fn send_key_update(&mut self, request_update: bool) -> io::Result<()> {
+        self.state.check_write()?;
+
+        if self.session.0.is_none() {
+            return Err(KtlsErrorKind::Unsupported.into_error());
+        }
+
+        let result = self.send_record(ContentType::Handshake, &key_update(request_update))
+            .and_then(|()| self.next_tx_key());
+        self.fail_key_update(result)
+    }
+
+    /// Follow the peer onto its new key, and move to a new key of our own if it asks for that.
+    ///
+    /// The records after a KeyUpdate are under the new key, so without a `Session` to derive
+    /// it, or on a kernel before Linux 6.13, the stream fails with `KeyUpdate`.
+    fn handle_key_update(&mut self, body: &[u8]) -> io::Result<()> {
+        let request_update = match *body {
+            [UPDATE_NOT_REQUESTED] => false,
+            [UPDATE_REQUESTED] => true,
+            _ => {
+                let _ = self.send_alert(Level::Fatal, AlertDescription::DecodeError);
+                let kind = KtlsErrorKind::AlertSent(AlertDescription::DecodeError);
+                self.state = State::Failed(kind);
+                return Err(kind.into_error());
+            }
+        };
+
+        let result = self.next_rx_key()
+            .and_then(|()| if request_update && self.state.can_write() {
+                // sent under the old key, everything after it goes under the new one
+                self.send_record(ContentType::Handshake, &key_update(false))?;
+                self.next_tx_key()
+            } else {
+                Ok(())
+            });
+        self.fail_key_update(result)
+    }
+
+    fn next_tx_key(&mut self) -> io::Result<()> {
+        let info = self.session()?.update_tx()?;
+        unsafe { sys::update_tx(&mut self.io, &info) }
+    }
+
+    fn next_rx_key(&mut self) -> io::Result<()> {
+        let info = self.session()?.update_rx()?;
+        unsafe { sys::update_rx(&mut self.io, &info) }
+    }
+
+    fn session(&mut self) -> io::Result<&mut Box<dyn Session>> {
+        self.session.0.as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no session to derive traffic keys"))
+    }
+
+    fn fail_key_update(&mut self, result: io::Result<()>) -> io::Result<()> {
+        result.map_err(|err| {
+            self.state = State::Failed(KtlsErrorKind::KeyUpdate);
+            KtlsErrorKind::KeyUpdate.with_source(err)
+        })
+    }
+
+    fn handle_ticket(&mut self, body: &[u8]) {
+        if let Some(session) = self.session.0.as_mut() {
+            if let Err(err) = session.handle_ticket(body) {
+                warn!("ignore session ticket the session did not take: {}", err);
+            }
+        }
+
+        if let Some(handler) = self.ticket_handler.0.as_mut() {
+            match SessionTicket::read(body) {
+                Some(ticket) => handler(ticket),
+                None => warn!("ignore malformed session ticket")
+            }
+        }
+    }
+
+    fn refuse_renegotiation(&mut self) -> io::Result<()> {
+        match self.renegotiation {
+            Renegotiation::Refuse if self.state.can_write() =>
+                self.send_alert(Level::Warning, AlertDescription::NoRenegotiation),
+            Renegotiation::Refuse => Ok(()),
+            Renegotiation::Fatal => {
+                let _ = self.send_alert(Level::Fatal, AlertDescription::HandshakeFailure);
+                self.state = State::Failed(KtlsErrorKind::Renegotiation);
+                Err(KtlsErrorKind::Renegotiation.into_error())
+            }
+        }
+    }
+}
@@ -1,17 +1,40 @@
 pub mod sys;
 pub mod codec;
+mod state;
+mod handshake;
 
-use std::{ error, fmt };
+#[cfg(feature = "rustls")]
+pub mod rustls;
+
+use std::{ error, fmt, cmp };
+use std::fs::File;
 use std::io::{ self, Read, Write };
+use std::net::{ Shutdown, TcpListener, TcpStream, Ipv4Addr };
+use std::sync::atomic::{ AtomicU8, Ordering };
+use std::ops::{ Bound, RangeBounds };
 use std::os::unix::io::{ AsRawFd, RawFd };
 use std::marker::PhantomData;
-use crate::codec::{ Record, Level, Alert };
-pub use crate::sys::tls12_crypto_info_aes_gcm_128 as Tls12CryptoInfoAesGcm128;
+use std::time::{ Duration, Instant };
+use log::warn;
+use crate::codec::{ Record, Level, AlertDescription, ContentType };
+pub use crate::state::{ State, CloseStatus };
+pub use crate::handshake::{ Renegotiation, SessionTicket, Session };
+use crate::handshake::{ TicketHandler, SessionHandle };
+pub use crate::sys::{ CryptoInfo, TlsVersion };
+#[cfg(feature = "rustls")]
+pub use crate::rustls::KtlsListener;
 
 
 #[derive(Debug)]
 pub struct KtlsStream<IO, R> {
     io: IO,
+    last_alert: Option<(Level, AlertDescription)>,
+    state: State,
+    renegotiation: Renegotiation,
+    ticket_handler: TicketHandler,
+    session: SessionHandle,
+    pending_handshake: Vec<u8>,
+    buffered: Vec<u8>,
     _phantom: PhantomData<R>
 }
 
@@ -30,6 +53,89 @@ impl<IO, R> KtlsStream<IO, R> {
     pub fn into_inner(self) -> IO {
         self.io
     }
+
+    /// The last alert received from the peer.
+    #[inline]
+    pub fn last_alert(&self) -> Option<(Level, AlertDescription)> {
+        self.last_alert
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Plaintext the TLS library received before install.
+    ///
+    /// It is returned by the first reads, ahead of anything from the socket.
+    pub fn set_buffered_plaintext(&mut self, buf: Vec<u8>) {
+        self.buffered = buf;
+    }
+
+    fn read_buffered(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.buffered.is_empty() || buf.is_empty() {
+            return None;
+        }
+
+        let n = cmp::min(buf.len(), self.buffered.len());
+        buf[..n].copy_from_slice(&self.buffered[..n]);
+        self.buffered.drain(..n);
+        Some(n)
+    }
+}
+
+/// Attach the `tls` ULP to `io` without installing keys.
+///
+/// This tells early whether kTLS can be used on the socket, before the TLS library
+/// gives up its session. The socket keeps working as plain TCP until `KtlsStream::new`.
+pub fn attach_ulp<IO: AsRawFd>(io: &mut IO) -> io::Result<()> {
+    unsafe {
+        sys::attach_ulp(io).map_err(KtlsErrorKind::from_install)
+    }
+}
+
+/// Whether the kernel takes keys of the version and cipher of `info`, for sending and receiving.
+///
+/// The first call for each version and cipher binds a listener on 127.0.0.1 and opens a
+/// TCP connection to it, to install the keys on; later calls return the answer it got.
+/// So this tells before the TLS library gives up its session whether the kernel would
+/// reject them. Fails with `UlpNotLoaded` if the `tls` ULP is not available, and with
+/// the error of the connection, as `EMFILE`, if it can not be opened.
+pub fn probe(info: &CryptoInfo) -> io::Result<bool> {
+    const UNKNOWN: u8 = 0;
+    const SUPPORTED: u8 = 1;
+    const REJECTED: u8 = 2;
+
+    static PROBED: [AtomicU8; 6] = [
+        AtomicU8::new(UNKNOWN), AtomicU8::new(UNKNOWN), AtomicU8::new(UNKNOWN),
+        AtomicU8::new(UNKNOWN), AtomicU8::new(UNKNOWN), AtomicU8::new(UNKNOWN)
+    ];
+
+    let probed = &PROBED[info.slot()];
+
+    match probed.load(Ordering::Relaxed) {
+        SUPPORTED => return Ok(true),
+        REJECTED => return Ok(false),
+        _ => ()
+    }
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let mut sock = TcpStream::connect(listener.local_addr()?)?;
+    let _peer = listener.accept()?;
+
+    let supported = match unsafe { sys::start(&mut sock, info, info) } {
+        Ok(()) => true,
+        Err(err) => {
+            let err = KtlsErrorKind::from_install(err);
+            match KtlsErrorKind::of(&err) {
+                Some(KtlsErrorKind::Unsupported) => false,
+                _ => return Err(err)
+            }
+        }
+    };
+
+    probed.store(if supported { SUPPORTED } else { REJECTED }, Ordering::Relaxed);
+    Ok(supported)
 }
 
 impl<IO, R> KtlsStream<IO, R>
@@ -37,31 +143,170 @@ where
     IO: Read + Write + AsRawFd,
     R: Record
 {
-    pub fn new(mut io: IO, tx: &Tls12CryptoInfoAesGcm128, rx: &Tls12CryptoInfoAesGcm128)
+    pub fn new(mut io: IO, tx: &CryptoInfo, rx: &CryptoInfo)
         -> Result<KtlsStream<IO, R>, Error<IO>>
     {
         unsafe {
             if let Err(error) = sys::start(&mut io, tx, rx) {
+                let error = KtlsErrorKind::from_install(error);
                 return Err(Error { error, inner: io });
             }
         }
 
-        Ok(KtlsStream { io, _phantom: PhantomData })
+        Ok(KtlsStream {
+            io,
+            last_alert: None,
+            state: State::Open,
+            renegotiation: Renegotiation::default(),
+            ticket_handler: TicketHandler::default(),
+            session: SessionHandle::default(),
+            pending_handshake: Vec::new(),
+            buffered: Vec::new(),
+            _phantom: PhantomData
+        })
     }
 }
 
 impl<IO: AsRawFd, R: Record> KtlsStream<IO, R> {
+    /// Close the write side. Does nothing if it is already closed.
     pub fn send_close_notify(&mut self) -> io::Result<()> {
-        const ALERT: u8 = 0x15;
+        match self.state {
+            State::WriteClosed | State::Closed => Ok(()),
+            _ => self.send_alert(Level::Warning, AlertDescription::CloseNotify)
+        }
+    }
 
-        let record = R::build(Level::Fatal, Alert::CloseNotify);
+    pub fn send_alert(&mut self, level: Level, desc: AlertDescription) -> io::Result<()> {
+        let record = R::build(level, desc);
+        self.send_record(ContentType::Alert, &record)?;
+
+        self.state = match (level, desc) {
+            (_, AlertDescription::CloseNotify) => self.state.close_write(),
+            (Level::Fatal, desc) => State::Failed(KtlsErrorKind::AlertSent(desc)),
+            (Level::Warning, _) => self.state
+        };
+
+        Ok(())
+    }
+
+    /// Shut down one or both directions, at the TLS layer and then at TCP.
+    ///
+    /// Closing the write side sends close_notify before the TCP FIN, so the peer
+    /// sees a clean end of stream while this side can keep reading. If the stream
+    /// can no longer write, for example after it failed, only TCP is shut down.
+    ///
+    /// If close_notify can not be sent, as with `WouldBlock`, nothing is shut down
+    /// and the error is returned, so the call can be retried.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let Shutdown::Write | Shutdown::Both = how {
+            if self.state.can_write() {
+                self.send_close_notify()?;
+            }
+        }
+
+        if let Shutdown::Read | Shutdown::Both = how {
+            self.state = self.state.close_read();
+        }
 
         unsafe {
-            sys::send_ctrl_message(&mut self.io, ALERT, &record)?;
+            sys::shutdown(&mut self.io, how)
+        }
+    }
+}
+
+impl<IO: AsRawFd, R> KtlsStream<IO, R> {
+    /// Send `buf` as one record of a control type.
+    ///
+    /// Application data is refused here, use `write` or `write_record` instead.
+    pub fn send_record(&mut self, typ: ContentType, buf: &[u8]) -> io::Result<()> {
+        let record_type = u8::from(typ);
+
+        if record_type == sys::TLS_RECORD_TYPE_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "application data can not be sent as a control record"
+            ));
+        }
+
+        self.send_whole_record(record_type, buf)
+    }
+
+    /// Write `buf` as exactly one application data record.
+    ///
+    /// Unlike `write`, the payload is never split across records or merged with
+    /// neighbouring writes. If the kernel takes only part of the payload, the rest
+    /// can no longer go out in the same record, so the stream fails with `SplitRecord`.
+    pub fn write_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.send_whole_record(sys::TLS_RECORD_TYPE_DATA, buf)
+    }
+
+    /// Send `header` followed by `range` of `file`.
+    ///
+    /// The header is sent with `MSG_MORE`, so that it shares a record with the first
+    /// bytes of the file instead of going out in a tiny record of its own.
+    pub fn send_with_file<B>(&mut self, header: &[u8], file: &File, range: B) -> io::Result<()>
+    where B: RangeBounds<u64>
+    {
+        let mut progress = SendFileProgress::new(file, range)?;
+        self.resume_send_with_file(&mut progress, header, file)
+    }
+
+    /// Continue a `send_with_file` that was interrupted, for example by `WouldBlock`.
+    pub fn resume_send_with_file(&mut self, progress: &mut SendFileProgress, header: &[u8], file: &File)
+        -> io::Result<()>
+    {
+        // sendfile transfers at most this much per call.
+        const MAX_COUNT: u64 = 0x7fff_f000;
+
+        self.state.check_write()?;
+
+        while progress.header < header.len() {
+            let flags = if progress.offset < progress.end { libc::MSG_MORE } else { 0 };
+            let n = unsafe {
+                sys::send(&mut self.io, &header[progress.header..], flags)?
+            };
+
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+
+            progress.header += n;
+        }
+
+        while progress.offset < progress.end {
+            let count = cmp::min(progress.end - progress.offset, MAX_COUNT) as usize;
+            let n = unsafe {
+                sys::sendfile(&mut self.io, file, &mut progress.offset, count)?
+            };
+
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than range"));
+            }
         }
 
         Ok(())
     }
+
+    fn send_whole_record(&mut self, record_type: u8, buf: &[u8]) -> io::Result<()> {
+        self.state.check_write()?;
+
+        if buf.len() > sys::TLS_MAX_PAYLOAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "record payload too large"));
+        }
+
+        // an explicit record type makes the kernel close any open record first.
+        let n = unsafe {
+            sys::send_ctrl_message(&mut self.io, record_type, buf)?
+        };
+
+        if n == buf.len() {
+            Ok(())
+        } else {
+            // the kernel already has the head of the record, there is no taking it back.
+            self.state = State::Failed(KtlsErrorKind::SplitRecord);
+            Err(KtlsErrorKind::SplitRecord.into_error())
+        }
+    }
 }
 
 /// TODO(quininer) need buff_size
@@ -71,6 +316,7 @@ impl<IO: AsRawFd, R: Record> KtlsStream<IO, R> {
 /// reached or an alert record needs to be sent.
 impl<IO: Write, R> Write for KtlsStream<IO, R> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state.check_write()?;
         self.io.write(buf)
     }
 
@@ -84,29 +330,170 @@ where
     IO: Read + AsRawFd,
     R: Record
 {
+    #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.io.read(buf) {
-            Ok(n) => Ok(n),
-            Err(ref err) if err.raw_os_error() == Some(5) => {
-                // FIXME too big?
-                let mut buf2 = [0; 16 * 1024];
-                let n = unsafe {
-                    sys::recv_ctrl_message(&mut self.io, &mut buf2)?
-                };
-
-                match R::check(&buf2[..n])? {
-                    Some((Level::Fatal, Alert::CloseNotify)) => {
-                        let _ = self.send_close_notify();
-                        return Ok(0);
+        self.read_data(buf, 0)
+    }
+}
+
+impl<IO, R> KtlsStream<IO, R>
+where
+    IO: Read + AsRawFd,
+    R: Record
+{
+    /// Read application data, handling any control records in front of it.
+    fn read_data(&mut self, buf: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+        if let Some(n) = self.read_buffered(buf) {
+            return Ok(n);
+        }
+
+        loop {
+            let (record_type, n) = self.recv(buf, flags)?;
+
+            let record_type = match record_type {
+                Some(sys::TLS_RECORD_TYPE_DATA) | None => return Ok(n),
+                Some(record_type) => record_type
+            };
+
+            let record = self.read_ctrl_record(record_type, &buf[..n])?;
+
+            match ContentType::from(record_type) {
+                ContentType::Alert => match R::check(&record)? {
+                    Some((level, desc)) => {
+                        self.last_alert = Some((level, desc));
+
+                        match (level, desc) {
+                            (_, AlertDescription::CloseNotify) => {
+                                self.state = self.state.close_read();
+                                return Ok(0);
+                            },
+                            (Level::Fatal, desc) => {
+                                let kind = KtlsErrorKind::AlertReceived(desc);
+                                self.state = State::Failed(kind);
+                                return Err(kind.into_error());
+                            },
+                            (Level::Warning, desc) => warn!("ignore warning alert: {:?}", desc)
+                        }
                     },
-                    // FIXME return Error?
-                    Some((Level::Fatal, _)) => return Ok(0),
-                    _ => ()
-                }
+                    None => ()
+                },
+                ContentType::Handshake => self.handle_handshake(&record[sys::TLS_HEADER_SIZE..])?,
+                _ => ()
+            }
+        }
+    }
+
+    /// Send close_notify, then discard incoming data until the peer's close_notify.
+    ///
+    /// The close is `Truncated` if the connection ends without it, or if `timeout`
+    /// passes first.
+    pub fn close_gracefully(&mut self, timeout: Option<Duration>) -> io::Result<CloseStatus> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut buf = [0; 1024];
+
+        self.send_close_notify()?;
+
+        loop {
+            match self.state {
+                State::Failed(kind) => return Err(kind.into_error()),
+                state if !state.can_read() => return Ok(CloseStatus::Clean),
+                _ => ()
+            }
 
-                self.read(buf)
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(CloseStatus::Truncated);
+                    }
+                    Some(deadline - now)
+                },
+                None => None
+            };
+
+            if !unsafe { sys::poll_read(&mut self.io, timeout)? } {
+                return Ok(CloseStatus::Truncated);
+            }
+
+            // poll only says a record has started to arrive, the rest of it may not
+            // come before the deadline.
+            match self.read_data(&mut buf, libc::MSG_DONTWAIT) {
+                Ok(0) if self.state.can_read() => return Ok(CloseStatus::Truncated),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset =>
+                    return Ok(CloseStatus::Truncated),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err)
+            }
+        }
+    }
+}
+
+impl<IO: AsRawFd, R: Record> KtlsStream<IO, R> {
+    /// Read a single record of any type, without interpreting it.
+    ///
+    /// If `buf` is smaller than the record, the rest of it is returned by the next call.
+    /// The kernel may join consecutive application data records into one read.
+    /// End of stream is reported as zero bytes of application data.
+    pub fn read_record(&mut self, buf: &mut [u8]) -> io::Result<(ContentType, usize)> {
+        if let Some(n) = self.read_buffered(buf) {
+            return Ok((ContentType::ApplicationData, n));
+        }
+
+        let (record_type, n) = self.recv(buf, 0)?;
+
+        let record_type = record_type.unwrap_or(sys::TLS_RECORD_TYPE_DATA);
+        Ok((record_type.into(), n))
+    }
+
+    /// Rebuild a control record that was received into the caller's buffer.
+    ///
+    /// A record larger than the buffer comes in over several `recv`s, and the kernel
+    /// does not mark where it ends. Handshake messages are put back together from the
+    /// pieces by `handle_handshake`, so only an alert cut short by a one byte buffer
+    /// is completed here. The rest is already decrypted, so this never waits on the socket.
+    fn read_ctrl_record(&mut self, record_type: u8, head: &[u8]) -> io::Result<Vec<u8>> {
+        const ALERT_LEN: usize = 2;
+
+        let mut record = vec![0; sys::TLS_HEADER_SIZE];
+        record.extend_from_slice(head);
+
+        if ContentType::from(record_type) == ContentType::Alert && head.len() < ALERT_LEN {
+            let mut rest = [0; ALERT_LEN];
+            let rest = &mut rest[..ALERT_LEN - head.len()];
+
+            match self.recv(rest, libc::MSG_DONTWAIT) {
+                Ok((Some(next_type), n)) if next_type == record_type =>
+                    record.extend_from_slice(&rest[..n]),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err)
+            }
+        }
+
+        let len = record.len() - sys::TLS_HEADER_SIZE;
+        sys::write_header(record_type, len, &mut record);
+        Ok(record)
+    }
+
+    /// Receive from the socket, failing the stream if a record does not authenticate.
+    ///
+    /// Once the read side is closed, this reports end of stream without touching the socket.
+    fn recv(&mut self, buf: &mut [u8], flags: libc::c_int) -> io::Result<(Option<u8>, usize)> {
+        match self.state {
+            State::Failed(kind) => return Err(kind.into_error()),
+            State::ReadClosed | State::Closed => return Ok((None, 0)),
+            State::Open | State::WriteClosed => ()
+        }
+
+        match unsafe { sys::recv_message(&mut self.io, buf, flags) } {
+            Err(ref err) if err.raw_os_error() == Some(libc::EBADMSG) => {
+                let _ = self.send_alert(Level::Fatal, AlertDescription::BadRecordMac);
+                self.state = State::Failed(KtlsErrorKind::BadRecordMac);
+                Err(KtlsErrorKind::BadRecordMac.into_error())
             },
-            Err(err) => Err(err)
+            result => result
         }
     }
 }
@@ -118,6 +505,40 @@ impl<IO: AsRawFd, R> AsRawFd for KtlsStream<IO, R> {
     }
 }
 
+/// Progress of a `send_with_file` call, kept so that a non-blocking caller can resume it.
+#[derive(Debug, Clone)]
+pub struct SendFileProgress {
+    header: usize,
+    offset: u64,
+    end: u64
+}
+
+impl SendFileProgress {
+    pub fn new<B: RangeBounds<u64>>(file: &File, range: B) -> io::Result<SendFileProgress> {
+        fn next(n: u64) -> io::Result<u64> {
+            n.checked_add(1)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file range out of bounds"))
+        }
+
+        let offset = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => next(n)?,
+            Bound::Unbounded => 0
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => next(n)?,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => file.metadata()?.len()
+        };
+
+        if offset > end {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "file range starts after it ends"));
+        }
+
+        Ok(SendFileProgress { header: 0, offset, end })
+    }
+}
+
 #[derive(Debug)]
 pub struct Error<T> {
     pub error: io::Error,
@@ -143,3 +564,129 @@ impl<T: fmt::Debug> error::Error for Error<T> {
         Some(&self.error)
     }
 }
+
+impl<T> Error<T> {
+    #[inline]
+    pub fn kind(&self) -> Option<KtlsErrorKind> {
+        KtlsErrorKind::of(&self.error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KtlsErrorKind {
+    /// The `tls` ULP is not available, usually because the module is not loaded.
+    UlpNotLoaded,
+    /// The cipher suite or protocol version is not supported.
+    Unsupported,
+    /// The socket is not a connected TCP socket.
+    NotConnected,
+    /// kTLS is already installed on this socket.
+    AlreadyInstalled,
+    /// The handshake has not completed yet.
+    HandshakeIncomplete,
+    /// The TLS library still has records queued, which could not be written out before install.
+    PendingWrite,
+    /// The TLS library read part of a record, which the kernel can not authenticate.
+    PartialRecord,
+    /// The peer sent a fatal alert.
+    AlertReceived(AlertDescription),
+    /// We sent a fatal alert.
+    AlertSent(AlertDescription),
+    /// A record failed authentication.
+    BadRecordMac,
+    /// Only part of a record passed to `write_record` or `send_record` was sent.
+    SplitRecord,
+    /// The peer tried to renegotiate, which the stream was set to treat as fatal.
+    Renegotiation,
+    /// The TLS 1.3 traffic keys changed, and the kernel could not be given the new ones.
+    KeyUpdate
+}
+
+impl KtlsErrorKind {
+    /// Find the kind of an error returned by this crate, if it has one.
+    pub fn of(err: &io::Error) -> Option<KtlsErrorKind> {
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<KtlsError>())
+            .map(|err| err.kind)
+    }
+
+    fn from_install(err: io::Error) -> io::Error {
+        let kind = match err.raw_os_error() {
+            Some(libc::ENOENT) => KtlsErrorKind::UlpNotLoaded,
+            Some(libc::EINVAL) | Some(libc::ENOPROTOOPT) | Some(libc::EOPNOTSUPP) =>
+                KtlsErrorKind::Unsupported,
+            Some(libc::ENOTCONN) => KtlsErrorKind::NotConnected,
+            Some(libc::EEXIST) | Some(libc::EBUSY) => KtlsErrorKind::AlreadyInstalled,
+            _ => return err
+        };
+
+        kind.with_source(err)
+    }
+
+    pub fn into_error(self) -> io::Error {
+        io::Error::new(self.io_kind(), KtlsError { kind: self, source: None })
+    }
+
+    pub fn with_source(self, source: io::Error) -> io::Error {
+        io::Error::new(self.io_kind(), KtlsError { kind: self, source: Some(source) })
+    }
+
+    fn io_kind(self) -> io::ErrorKind {
+        match self {
+            KtlsErrorKind::UlpNotLoaded => io::ErrorKind::NotFound,
+            KtlsErrorKind::Unsupported => io::ErrorKind::InvalidInput,
+            KtlsErrorKind::NotConnected => io::ErrorKind::NotConnected,
+            KtlsErrorKind::AlreadyInstalled => io::ErrorKind::AlreadyExists,
+            KtlsErrorKind::HandshakeIncomplete => io::ErrorKind::Other,
+            KtlsErrorKind::PendingWrite => io::ErrorKind::Other,
+            KtlsErrorKind::PartialRecord => io::ErrorKind::InvalidData,
+            KtlsErrorKind::AlertReceived(_) => io::ErrorKind::ConnectionAborted,
+            KtlsErrorKind::AlertSent(_) => io::ErrorKind::ConnectionAborted,
+            KtlsErrorKind::BadRecordMac => io::ErrorKind::InvalidData,
+            KtlsErrorKind::SplitRecord => io::ErrorKind::WriteZero,
+            KtlsErrorKind::Renegotiation => io::ErrorKind::ConnectionAborted,
+            KtlsErrorKind::KeyUpdate => io::ErrorKind::Other
+        }
+    }
+}
+
+impl fmt::Display for KtlsErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KtlsErrorKind::UlpNotLoaded => f.write_str("tls ulp is not available, is the tls module loaded?"),
+            KtlsErrorKind::Unsupported => f.write_str("protocol/algorithm is not supported"),
+            KtlsErrorKind::NotConnected => f.write_str("socket is not connected"),
+            KtlsErrorKind::AlreadyInstalled => f.write_str("ktls is already installed"),
+            KtlsErrorKind::HandshakeIncomplete => f.write_str("handshake is not completed"),
+            KtlsErrorKind::PendingWrite => f.write_str("tls session has unsent records"),
+            KtlsErrorKind::PartialRecord => f.write_str("tls session holds part of a record"),
+            KtlsErrorKind::AlertReceived(desc) => write!(f, "received fatal alert: {:?}", desc),
+            KtlsErrorKind::AlertSent(desc) => write!(f, "sent fatal alert: {:?}", desc),
+            KtlsErrorKind::BadRecordMac => f.write_str("record authentication failed"),
+            KtlsErrorKind::SplitRecord => f.write_str("record was only partly sent"),
+            KtlsErrorKind::Renegotiation => f.write_str("peer attempted renegotiation"),
+            KtlsErrorKind::KeyUpdate => f.write_str("traffic keys could not be updated")
+        }
+    }
+}
+
+#[derive(Debug)]
+struct KtlsError {
+    kind: KtlsErrorKind,
+    source: Option<io::Error>
+}
+
+impl fmt::Display for KtlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Some(ref source) => write!(f, "{}: {}", self.kind, source),
+            None => self.kind.fmt(f)
+        }
+    }
+}
+
+impl error::Error for KtlsError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        self.source.as_ref().map(|err| err as _)
+    }
+}
@@ -0,0 +1,110 @@
+use std::io;
+use sha2::{ Digest, Sha224, Sha256, Sha384, Sha512 };
+
+
+/// Channel binding types, from RFC 5929 and RFC 9266.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelBinding {
+    /// `tls-server-end-point`, a hash of the server certificate.
+    TlsServerEndPoint,
+    /// `tls-exporter`, keying material exported from the session. TLS 1.3 only.
+    TlsExporter
+}
+
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+pub const EXPORTER_LEN: usize = 32;
+
+const OID_PKCS1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01];
+const OID_ECDSA_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01];
+const OID_ECDSA_SHA2: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03];
+
+enum Hash {
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512
+}
+
+/// Hash `cert` with the hash of its signature algorithm, where MD5 and SHA-1
+/// are replaced by SHA-256 and any other hash is kept, as RFC 5929 section 4.1 says.
+pub fn server_end_point(cert: &[u8]) -> io::Result<Vec<u8>> {
+    let oid = signature_algorithm(cert)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unable to decode certificate"))?;
+
+    let hash = match oid.split_last() {
+        // md5WithRSAEncryption, sha1WithRSAEncryption, sha256WithRSAEncryption
+        Some((0x04, pkcs1)) | Some((0x05, pkcs1)) | Some((0x0b, pkcs1)) if pkcs1 == OID_PKCS1 =>
+            Some(Hash::Sha256),
+        // sha224WithRSAEncryption
+        Some((0x0e, pkcs1)) if pkcs1 == OID_PKCS1 => Some(Hash::Sha224),
+        Some((0x0c, pkcs1)) if pkcs1 == OID_PKCS1 => Some(Hash::Sha384),
+        Some((0x0d, pkcs1)) if pkcs1 == OID_PKCS1 => Some(Hash::Sha512),
+        _ if oid == OID_ECDSA_SHA1 => Some(Hash::Sha256),
+        Some((0x01, ecdsa)) if ecdsa == OID_ECDSA_SHA2 => Some(Hash::Sha224),
+        Some((0x02, ecdsa)) if ecdsa == OID_ECDSA_SHA2 => Some(Hash::Sha256),
+        Some((0x03, ecdsa)) if ecdsa == OID_ECDSA_SHA2 => Some(Hash::Sha384),
+        Some((0x04, ecdsa)) if ecdsa == OID_ECDSA_SHA2 => Some(Hash::Sha512),
+
+        // RSASSA-PSS, Ed25519 and others have no hash defined for this
+        _ => None
+    };
+
+    let hash = hash.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "tls-server-end-point is not defined for the certificate signature algorithm"
+    ))?;
+
+    Ok(match hash {
+        Hash::Sha224 => Sha224::digest(cert).to_vec(),
+        Hash::Sha256 => Sha256::digest(cert).to_vec(),
+        Hash::Sha384 => Sha384::digest(cert).to_vec(),
+        Hash::Sha512 => Sha512::digest(cert).to_vec()
+    })
+}
+
+/// The signature algorithm OID of a DER certificate.
+///
+/// ```text
+/// Certificate ::= SEQUENCE {
+///     tbsCertificate       TBSCertificate,
+///     signatureAlgorithm   AlgorithmIdentifier,
+///     signatureValue       BIT STRING }
+/// ```
+fn signature_algorithm(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const OID: u8 = 0x06;
+
+    let (cert, _) = der_take(SEQUENCE, cert)?;
+    let (_, cert) = der_take(SEQUENCE, cert)?;
+    let (algorithm, _) = der_take(SEQUENCE, cert)?;
+    let (oid, _) = der_take(OID, algorithm)?;
+    Some(oid)
+}
+
+/// Split a DER value with `tag` off the front of `buf`, returning its contents and the rest.
+fn der_take(tag: u8, buf: &[u8]) -> Option<(&[u8], &[u8])> {
+    match *buf {
+        [t, len, ref rest @ ..] if t == tag => {
+            let (len, rest) = match len {
+                0..=0x7f => (len as usize, rest),
+                0x81..=0x84 => {
+                    let n = (len & 0x7f) as usize;
+                    if rest.len() < n {
+                        return None;
+                    }
+
+                    let len = rest[..n].iter().fold(0, |len, &b| (len << 8) | b as usize);
+                    (len, &rest[n..])
+                },
+                _ => return None
+            };
+
+            if rest.len() < len {
+                return None;
+            }
+
+            Some(rest.split_at(len))
+        },
+        _ => None
+    }
+}
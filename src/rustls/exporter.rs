@@ -0,0 +1,108 @@
+use std::{ fmt, io };
+use std::sync::{ Arc, Mutex };
+use ::rustls::{ KeyLog, ClientConfig, ServerConfig, SupportedCipherSuite };
+use ::rustls::crypto::tls13::{ HkdfExpander, OkmBlock };
+
+
+const EXPORTER_SECRET: &str = "EXPORTER_SECRET";
+
+/// A key log that keeps the TLS 1.3 exporter secret of one connection, so keying
+/// material can still be exported once kTLS is installed.
+///
+/// Everything is also passed on to the key log the config had.
+#[derive(Debug)]
+pub struct ExporterLog {
+    inner: Arc<dyn KeyLog>,
+    secret: Mutex<Option<ExporterSecret>>
+}
+
+impl ExporterLog {
+    /// A copy of `config` logging to a new `ExporterLog`, for one connection.
+    pub fn client(config: &Arc<ClientConfig>) -> (Arc<ClientConfig>, Arc<ExporterLog>) {
+        let log = ExporterLog::wrap(config.key_log.clone());
+        let mut config = ClientConfig::clone(config);
+        config.key_log = log.clone();
+        (Arc::new(config), log)
+    }
+
+    /// A copy of `config` logging to a new `ExporterLog`, for one connection.
+    pub fn server(config: &Arc<ServerConfig>) -> (Arc<ServerConfig>, Arc<ExporterLog>) {
+        let log = ExporterLog::wrap(config.key_log.clone());
+        let mut config = ServerConfig::clone(config);
+        config.key_log = log.clone();
+        (Arc::new(config), log)
+    }
+
+    fn wrap(inner: Arc<dyn KeyLog>) -> Arc<ExporterLog> {
+        Arc::new(ExporterLog { inner, secret: Mutex::new(None) })
+    }
+
+    pub(crate) fn take(&self) -> Option<ExporterSecret> {
+        self.secret.lock().unwrap().take()
+    }
+}
+
+impl KeyLog for ExporterLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        if label == EXPORTER_SECRET {
+            *self.secret.lock().unwrap() = Some(ExporterSecret(secret.to_vec()));
+        }
+
+        if self.inner.will_log(label) {
+            self.inner.log(label, client_random, secret);
+        }
+    }
+
+    fn will_log(&self, label: &str) -> bool {
+        label == EXPORTER_SECRET || self.inner.will_log(label)
+    }
+}
+
+/// The TLS 1.3 exporter master secret of a connection.
+#[derive(Clone)]
+pub(crate) struct ExporterSecret(Vec<u8>);
+
+impl fmt::Debug for ExporterSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ExporterSecret(..)")
+    }
+}
+
+impl ExporterSecret {
+    /// Keying material as in RFC 8446 section 7.5.
+    pub fn export(&self, suite: SupportedCipherSuite, label: &[u8], context: Option<&[u8]>, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        let suite = suite.tls13()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a TLS 1.3 cipher suite"))?;
+        let hash = suite.common.hash_provider;
+
+        let expander = suite.hkdf_provider.expander_for_okm(&OkmBlock::new(&self.0));
+        let mut secret = vec![0; expander.hash_len()];
+        expand_label(expander.as_ref(), label, hash.hash(&[]).as_ref(), &mut secret)?;
+
+        let expander = suite.hkdf_provider.expander_for_okm(&OkmBlock::new(&secret));
+        let mut output = vec![0; len];
+        expand_label(expander.as_ref(), b"exporter", hash.hash(context.unwrap_or(&[])).as_ref(), &mut output)?;
+        Ok(output)
+    }
+}
+
+/// HKDF-Expand-Label from RFC 8446 section 7.1.
+fn expand_label(expander: &dyn HkdfExpander, label: &[u8], context: &[u8], output: &mut [u8])
+    -> io::Result<()>
+{
+    const PREFIX: &[u8] = b"tls13 ";
+
+    if output.len() > u16::max_value() as usize || PREFIX.len() + label.len() > u8::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "label or length too long"));
+    }
+
+    let len = (output.len() as u16).to_be_bytes();
+    let label_len = [(PREFIX.len() + label.len()) as u8];
+    let context_len = [context.len() as u8];
+    let info = [&len[..], &label_len, PREFIX, label, &context_len, context];
+
+    expander.expand_slice(&info, output)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "length too long"))
+}
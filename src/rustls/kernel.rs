@@ -0,0 +1,288 @@
+use std::io::{ self, Read, Write };
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::os::unix::io::AsRawFd;
+use ::rustls::{
+    ClientConfig, ServerConfig, ProtocolVersion, ExtractedSecrets, ConnectionTrafficSecrets,
+    Error as TlsError
+};
+use ::rustls::client::{ ClientConnectionData, UnbufferedClientConnection };
+use ::rustls::server::{ ServerConnectionData, UnbufferedServerConnection };
+use ::rustls::kernel::KernelConnection;
+use ::rustls::unbuffered::{
+    UnbufferedConnectionCommon, UnbufferedStatus, ConnectionState, ReadEarlyData,
+    EncodeError, InsufficientSizeError
+};
+use ::rustls::pki_types::ServerName;
+use crate::{ KtlsStream as InnerStream, KtlsErrorKind, CryptoInfo, TlsVersion, Session };
+use super::{ KtlsStream, Handshake, ExporterLog, RecordReader, crypto_info };
+use super::binding::{ EXPORTER_LABEL, EXPORTER_LEN };
+
+
+impl Session for KernelConnection<ClientConnectionData> {
+    fn handle_ticket(&mut self, body: &[u8]) -> io::Result<()> {
+        self.handle_new_session_ticket(body)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    #[inline]
+    fn update_tx(&mut self) -> io::Result<CryptoInfo> {
+        next_key(self.update_tx_secret())
+    }
+
+    #[inline]
+    fn update_rx(&mut self) -> io::Result<CryptoInfo> {
+        next_key(self.update_rx_secret())
+    }
+}
+
+impl Session for KernelConnection<ServerConnectionData> {
+    fn handle_ticket(&mut self, _body: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "session ticket from a client"))
+    }
+
+    #[inline]
+    fn update_tx(&mut self) -> io::Result<CryptoInfo> {
+        next_key(self.update_tx_secret())
+    }
+
+    #[inline]
+    fn update_rx(&mut self) -> io::Result<CryptoInfo> {
+        next_key(self.update_rx_secret())
+    }
+}
+
+/// Kernel parameters of a key rustls derived for a KeyUpdate, which only TLS 1.3 has.
+fn next_key(secrets: Result<(u64, ConnectionTrafficSecrets), TlsError>) -> io::Result<CryptoInfo> {
+    let secrets = secrets.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    crypto_info(TlsVersion::Tls13, secrets)
+}
+
+/// The two sides of the unbuffered API of rustls, which share no trait.
+trait Unbuffered {
+    type Data;
+
+    fn process_tls_records<'c, 'i>(&'c mut self, incoming: &'i mut [u8])
+        -> UnbufferedStatus<'c, 'i, Self::Data>;
+
+    /// Append the 0-RTT data of `state` to `early_data`, and return how much more to discard.
+    fn read_early_data(state: &mut ReadEarlyData<'_, '_, Self::Data>, early_data: &mut Vec<u8>)
+        -> io::Result<usize>;
+}
+
+impl Unbuffered for UnbufferedConnectionCommon<ClientConnectionData> {
+    type Data = ClientConnectionData;
+
+    #[inline]
+    fn process_tls_records<'c, 'i>(&'c mut self, incoming: &'i mut [u8])
+        -> UnbufferedStatus<'c, 'i, Self::Data>
+    {
+        UnbufferedConnectionCommon::<ClientConnectionData>::process_tls_records(self, incoming)
+    }
+
+    fn read_early_data(_state: &mut ReadEarlyData<'_, '_, Self::Data>, _early_data: &mut Vec<u8>)
+        -> io::Result<usize>
+    {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "early data from a server"))
+    }
+}
+
+impl Unbuffered for UnbufferedConnectionCommon<ServerConnectionData> {
+    type Data = ServerConnectionData;
+
+    #[inline]
+    fn process_tls_records<'c, 'i>(&'c mut self, incoming: &'i mut [u8])
+        -> UnbufferedStatus<'c, 'i, Self::Data>
+    {
+        UnbufferedConnectionCommon::<ServerConnectionData>::process_tls_records(self, incoming)
+    }
+
+    fn read_early_data(state: &mut ReadEarlyData<'_, '_, Self::Data>, early_data: &mut Vec<u8>)
+        -> io::Result<usize>
+    {
+        let mut discard = 0;
+
+        while let Some(record) = state.next_record() {
+            let record = record.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            discard += record.discard;
+            early_data.extend_from_slice(record.payload);
+        }
+
+        Ok(discard)
+    }
+}
+
+/// What an unbuffered handshake left behind, besides the connection.
+#[derive(Debug, Default)]
+struct Finished {
+    plaintext: Vec<u8>,
+    early_data: Option<Vec<u8>>,
+    read_closed: bool
+}
+
+/// Run a blocking client handshake with `server_name` on `io` through the unbuffered
+/// API of rustls, then install kTLS with the `KernelConnection` rustls gives up.
+///
+/// Unlike `connect`, rustls keeps its part of the connection, so TLS 1.3 session
+/// tickets the server sends after install go to the resumption store of `config`,
+/// and later connections made with it can resume. It also derives the keys for
+/// TLS 1.3 KeyUpdate, in both directions.
+pub fn connect_kernel<IO>(mut io: IO, config: Arc<ClientConfig>, server_name: ServerName<'static>)
+    -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    let (config, log) = ExporterLog::client(&config);
+    let mut conn = UnbufferedClientConnection::new(config, server_name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let finished = complete_handshake(&mut *conn, &mut io)?;
+
+    let mut handshake = handshake_info(&*conn, &log)?;
+    handshake.server_certificate = conn.peer_certificates().and_then(|certs| certs.first().cloned());
+    let (secrets, kernel) = conn.dangerous_into_kernel_connection()
+        .map_err(into_unsupported)?;
+    install(io, secrets, kernel, handshake, finished)
+}
+
+/// Run a blocking server handshake on `io` through the unbuffered API of rustls,
+/// then install kTLS with the `KernelConnection` rustls gives up, which derives
+/// the keys for TLS 1.3 KeyUpdate.
+///
+/// The unbuffered API does not tell the SNI the client sent, so `server_name`
+/// is always `None` on the result.
+pub fn accept_kernel<IO>(mut io: IO, config: Arc<ServerConfig>) -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    let (config, log) = ExporterLog::server(&config);
+    let mut conn = UnbufferedServerConnection::new(config)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut finished = complete_handshake(&mut *conn, &mut io)?;
+
+    let mut handshake = handshake_info(&*conn, &log)?;
+    handshake.early_data = finished.early_data.take();
+    let (secrets, kernel) = conn.dangerous_into_kernel_connection()
+        .map_err(into_unsupported)?;
+    install(io, secrets, kernel, handshake, finished)
+}
+
+/// Drive the handshake of `conn` on `io` until rustls can send application data.
+///
+/// Records are read whole, one at a time, so nothing past the handshake is taken
+/// from the socket. Application data rustls decrypted on the way is kept for the
+/// first reads of the stream.
+fn complete_handshake<C, IO>(conn: &mut C, io: &mut IO) -> io::Result<Finished>
+where
+    C: Unbuffered,
+    IO: Read + Write
+{
+    let mut records = RecordReader::new();
+    let mut incoming = Vec::new();
+    let mut outgoing = Vec::new();
+    let mut finished = Finished::default();
+
+    loop {
+        let UnbufferedStatus { mut discard, state } = conn.process_tls_records(&mut incoming);
+        let state = state.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        match state {
+            ConnectionState::EncodeTlsData(mut state) => {
+                let start = outgoing.len();
+
+                loop {
+                    match state.encode(&mut outgoing[start..]) {
+                        Ok(n) => {
+                            outgoing.truncate(start + n);
+                            break
+                        },
+                        Err(EncodeError::InsufficientSize(InsufficientSizeError { required_size })) =>
+                            outgoing.resize(start + required_size, 0),
+                        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err))
+                    }
+                }
+            },
+            ConnectionState::TransmitTlsData(state) => {
+                io.write_all(&outgoing)?;
+                outgoing.clear();
+                state.done();
+            },
+            ConnectionState::BlockedHandshake => {
+                if records.read_record(&mut incoming, io)? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            },
+            ConnectionState::ReadTraffic(mut state) => while let Some(record) = state.next_record() {
+                let record = record.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                discard += record.discard;
+                finished.plaintext.extend_from_slice(record.payload);
+            },
+            ConnectionState::ReadEarlyData(mut state) => {
+                let early_data = finished.early_data.get_or_insert_with(Vec::new);
+                discard += C::read_early_data(&mut state, early_data)?;
+            },
+            ConnectionState::PeerClosed => finished.read_closed = true,
+            ConnectionState::WriteTraffic(_) | ConnectionState::Closed => break,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "unexpected unbuffered state"))
+        }
+
+        incoming.drain(..discard);
+    }
+
+    io.flush()?;
+    Ok(finished)
+}
+
+/// What the handshake of a finished `conn` negotiated.
+fn handshake_info<D>(conn: &UnbufferedConnectionCommon<D>, log: &ExporterLog)
+    -> io::Result<Handshake>
+{
+    let (version, suite) = match (conn.protocol_version(), conn.negotiated_cipher_suite()) {
+        (Some(version), Some(suite)) => (version, suite),
+        _ => return Err(KtlsErrorKind::HandshakeIncomplete.into_error())
+    };
+
+    let exporter_secret = log.take();
+    let exporter_binding = match exporter_secret {
+        Some(ref secret) if version == ProtocolVersion::TLSv1_3 =>
+            Some(secret.export(suite, EXPORTER_LABEL, None, EXPORTER_LEN)?),
+        _ => None
+    };
+
+    Ok(Handshake {
+        version, suite, exporter_binding, exporter_secret,
+        exports: Vec::new(),
+        early_data: None,
+        alpn_protocol: conn.alpn_protocol().map(Vec::from),
+        peer_certificates: conn.peer_certificates().map(Vec::from),
+        server_name: None,
+        server_certificate: None
+    })
+}
+
+fn install<IO, D>(io: IO, secrets: ExtractedSecrets, kernel: KernelConnection<D>, handshake: Handshake, finished: Finished)
+    -> io::Result<KtlsStream<IO>>
+where
+    IO: Read + Write + AsRawFd,
+    KernelConnection<D>: Session + 'static
+{
+    let version = match kernel.protocol_version() {
+        ProtocolVersion::TLSv1_2 => TlsVersion::Tls12,
+        ProtocolVersion::TLSv1_3 => TlsVersion::Tls13,
+        _ => return Err(KtlsErrorKind::Unsupported.into_error())
+    };
+
+    let tx = crypto_info(version, secrets.tx)?;
+    let rx = crypto_info(version, secrets.rx)?;
+
+    let mut kstream = InnerStream::new(io, &tx, &rx).map_err(|err| err.error)?;
+    kstream.set_buffered_plaintext(finished.plaintext);
+    kstream.set_session(kernel);
+
+    if finished.read_closed {
+        kstream.shutdown(Shutdown::Read)?;
+    }
+
+    Ok(KtlsStream { io: kstream, handshake })
+}
+
+fn into_unsupported(err: TlsError) -> io::Error {
+    KtlsErrorKind::Unsupported.with_source(io::Error::new(io::ErrorKind::Other, err))
+}
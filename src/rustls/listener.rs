@@ -0,0 +1,343 @@
+use std::{ io, cmp, thread };
+use std::io::{ Read, Write };
+use std::sync::{ Arc, Weak, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ self, Receiver, SyncSender };
+use std::net::{ TcpListener, TcpStream, SocketAddr };
+use std::os::unix::io::AsRawFd;
+use std::time::{ Duration, Instant };
+use ::rustls::{ ServerConfig, ServerConnection };
+use crate::sys;
+use super::{ KtlsStream, RecordReader, ExporterLog };
+
+/// How often a worker waiting for a connection checks whether it should stop.
+const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bounds of the pause after an accept fails for lack of resources, as with `EMFILE`.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+
+/// A TCP listener that runs a rustls handshake on each connection and installs kTLS.
+#[derive(Debug)]
+pub struct KtlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    timeout: Option<Duration>,
+    workers: usize,
+    exports: Arc<Vec<(Vec<u8>, Option<Vec<u8>>, usize)>>,
+    pool: Mutex<Weak<Pool>>,
+    turn: Arc<Mutex<()>>
+}
+
+impl KtlsListener {
+    /// The config needs `enable_secret_extraction` set.
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>) -> KtlsListener {
+        KtlsListener {
+            listener,
+            config,
+            timeout: None,
+            workers: 0,
+            exports: Arc::new(Vec::new()),
+            pool: Mutex::new(Weak::new()),
+            turn: Arc::new(Mutex::new(()))
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Give up on a handshake that takes longer than `timeout` in total,
+    /// with a `TimedOut` error.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Run handshakes for `incoming` on `workers` threads, taking turns to accept,
+    /// so a slow client only holds up one of them. With `0`, the default, handshakes
+    /// run on the thread iterating `incoming`.
+    ///
+    /// The threads start with the first `incoming`, are shared by the iterators alive
+    /// at the same time, and stop once all of them are dropped. At most `workers`
+    /// finished handshakes wait to be taken from the iterator.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers;
+    }
+
+    /// Export keying material for each `(label, context, len)` from every session,
+    /// to be read back through `Handshake::export_keying_material`.
+    /// Only needed on TLS 1.2, TLS 1.3 streams derive any material on demand.
+    pub fn set_exports(&mut self, exports: &[(&[u8], Option<&[u8]>, usize)]) {
+        let exports = exports.iter()
+            .map(|&(label, context, len)| (label.to_vec(), context.map(Vec::from), len))
+            .collect();
+        self.exports = Arc::new(exports);
+    }
+
+    /// Accept a connection and run its handshake on this thread.
+    ///
+    /// This takes turns on the listener with the workers of `incoming`, see `Worker::accept`.
+    pub fn accept(&self) -> io::Result<(KtlsStream<TcpStream>, SocketAddr)> {
+        let (sock, addr) = loop {
+            // give the turn back now and then, so waiting workers can see `stop`
+            let _turn = self.turn.lock().unwrap();
+
+            match unsafe { sys::poll_read(&mut self.listener.as_raw_fd(), Some(STOP_INTERVAL)) } {
+                Ok(true) => break self.listener.accept()?,
+                Ok(false) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err)
+            }
+        };
+        let stream = handshake(sock, &self.config, self.timeout, &self.exports)?;
+        Ok((stream, addr))
+    }
+
+    /// An iterator over installed streams.
+    ///
+    /// A connection that fails its handshake comes out as an `Err`, and the iterator
+    /// goes on with the next one, as `TcpListener::incoming` does with failed accepts.
+    pub fn incoming(&self) -> Incoming<'_> {
+        if self.workers == 0 {
+            return Incoming(Inner::Direct(self));
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+
+        let running = match pool.upgrade() {
+            Some(running) => running,
+            None => {
+                let running = Arc::new(self.start_pool());
+                *pool = Arc::downgrade(&running);
+                running
+            }
+        };
+
+        Incoming(Inner::Pool(running))
+    }
+
+    fn start_pool(&self) -> Pool {
+        let (send, recv) = mpsc::sync_channel(self.workers);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        for _ in 0..self.workers {
+            let listener = match self.listener.try_clone() {
+                Ok(listener) => listener,
+                Err(err) => {
+                    let _ = send.try_send(Err(err));
+                    continue
+                }
+            };
+            let worker = Worker {
+                listener,
+                config: self.config.clone(),
+                timeout: self.timeout,
+                exports: self.exports.clone(),
+                turn: self.turn.clone(),
+                stop: stop.clone(),
+                send: send.clone()
+            };
+
+            thread::spawn(move || worker.run());
+        }
+
+        Pool { recv: Mutex::new(recv), stop }
+    }
+}
+
+/// Worker threads of a listener, stopped when dropped.
+#[derive(Debug)]
+struct Pool {
+    recv: Mutex<Receiver<io::Result<KtlsStream<TcpStream>>>>,
+    stop: Arc<AtomicBool>
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // workers blocked on a full channel are let go when the receiver drops
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Worker {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    timeout: Option<Duration>,
+    exports: Arc<Vec<(Vec<u8>, Option<Vec<u8>>, usize)>>,
+    turn: Arc<Mutex<()>>,
+    stop: Arc<AtomicBool>,
+    send: SyncSender<io::Result<KtlsStream<TcpStream>>>
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut backoff = MIN_BACKOFF;
+
+        while !self.stop.load(Ordering::Relaxed) {
+            let result = match self.accept() {
+                Ok(Some(sock)) => {
+                    backoff = MIN_BACKOFF;
+                    handshake(sock, &self.config, self.timeout, &self.exports)
+                },
+                Ok(None) => continue,
+                Err(err) => {
+                    if is_out_of_resources(&err) {
+                        thread::sleep(backoff);
+                        backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                    }
+
+                    Err(err)
+                }
+            };
+
+            // stop once the iterators are dropped
+            if self.send.send(result).is_err() {
+                break
+            }
+        }
+    }
+
+    /// Accept a connection, or `None` if there was none for a while.
+    ///
+    /// Only one thread waits on the listener at a time, workers across pools and
+    /// `KtlsListener::accept` alike, so that a wakeup can not be taken by another
+    /// thread and leave this one blocked in `accept`, where it would not see `stop`.
+    fn accept(&mut self) -> io::Result<Option<TcpStream>> {
+        let _turn = self.turn.lock().unwrap();
+
+        match unsafe { sys::poll_read(&mut self.listener, Some(STOP_INTERVAL)) } {
+            // leave the connection to a newer pool, or to `accept`
+            Ok(true) if self.stop.load(Ordering::Relaxed) => Ok(None),
+            Ok(true) => self.listener.accept().map(|(sock, _)| Some(sock)),
+            Ok(false) => Ok(None),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+/// Whether an accept failed for lack of descriptors or memory, which retrying
+/// right away does not fix.
+fn is_out_of_resources(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => true,
+        _ => false
+    }
+}
+
+/// Iterator returned by `KtlsListener::incoming`.
+#[derive(Debug)]
+pub struct Incoming<'a>(Inner<'a>);
+
+#[derive(Debug)]
+enum Inner<'a> {
+    Direct(&'a KtlsListener),
+    Pool(Arc<Pool>)
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = io::Result<KtlsStream<TcpStream>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &self.0 {
+            Inner::Direct(listener) => Some(listener.accept().map(|(stream, _)| stream)),
+            Inner::Pool(pool) => pool.recv.lock().unwrap().recv().ok()
+        }
+    }
+}
+
+/// Run the server handshake on an accepted `sock` and install kTLS.
+fn handshake(
+    mut sock: TcpStream,
+    config: &Arc<ServerConfig>,
+    timeout: Option<Duration>,
+    exports: &[(Vec<u8>, Option<Vec<u8>>, usize)]
+) -> io::Result<KtlsStream<TcpStream>> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let (config, log) = ExporterLog::server(config);
+    let mut session = ServerConnection::new(config)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .into();
+    RecordReader::new().complete_handshake(&mut session, &mut Deadline { sock: &mut sock, deadline })?;
+
+    let exports = exports.iter()
+        .map(|(label, context, len)| (label.as_slice(), context.as_ref().map(Vec::as_slice), *len))
+        .collect::<Vec<_>>();
+    let mut stream = KtlsStream::with_exports(sock, session, &exports).map_err(|err| err.error)?;
+    stream.handshake_mut().set_exporter_log(&log);
+
+    if deadline.is_some() {
+        let sock = stream.get_mut().get_mut();
+        sock.set_read_timeout(None)?;
+        sock.set_write_timeout(None)?;
+    }
+
+    Ok(stream)
+}
+
+/// A socket whose reads and writes all give up at `deadline`, with `TimedOut`.
+struct Deadline<'a> {
+    sock: &'a mut TcpStream,
+    deadline: Option<Instant>
+}
+
+impl Deadline<'_> {
+    /// What is left until the deadline, failing once it passed.
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    Err(timed_out())
+                } else {
+                    Ok(Some(deadline - now))
+                }
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(remaining) = self.remaining()? {
+            self.sock.set_read_timeout(Some(remaining))?;
+        }
+
+        match self.sock.read(buf) {
+            Err(ref err) if self.deadline.is_some() && err.kind() == io::ErrorKind::WouldBlock =>
+                Err(timed_out()),
+            result => result
+        }
+    }
+}
+
+impl Write for Deadline<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(remaining) = self.remaining()? {
+            self.sock.set_write_timeout(Some(remaining))?;
+        }
+
+        match self.sock.write(buf) {
+            Err(ref err) if self.deadline.is_some() && err.kind() == io::ErrorKind::WouldBlock =>
+                Err(timed_out()),
+            result => result
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")
+}
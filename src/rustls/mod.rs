@@ -0,0 +1,635 @@
+//! Setting up kTLS from a rustls session, behind the `rustls` feature.
+
+mod binding;
+mod exporter;
+mod kernel;
+mod listener;
+mod record;
+
+use std::io::{ self, Read, Write };
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::os::unix::io::{ AsRawFd, RawFd };
+use ::rustls::{
+    ClientConfig, ServerConfig, ClientConnection, ServerConnection, Connection,
+    ConnectionTrafficSecrets, ProtocolVersion, CipherSuite, SupportedCipherSuite
+};
+use ::rustls::pki_types::{ CertificateDer, ServerName };
+use crate::{
+    KtlsStream as InnerStream, KtlsErrorKind, CryptoInfo, TlsVersion, Error,
+    State, Renegotiation, SessionTicket
+};
+use crate::codec::{ self, Record, Level, AlertDescription, ContentType };
+use crate::sys::TLS_HEADER_SIZE;
+use self::binding::{ EXPORTER_LABEL, EXPORTER_LEN };
+use self::exporter::ExporterSecret;
+
+pub use self::binding::ChannelBinding;
+pub use self::exporter::ExporterLog;
+pub use self::kernel::{ connect_kernel, accept_kernel };
+pub use self::listener::{ KtlsListener, Incoming };
+pub use self::record::RecordReader;
+
+
+#[derive(Debug)]
+pub enum Rustls {}
+
+const ALERT_LEVEL_WARNING: u8 = 1;
+const ALERT_LEVEL_FATAL: u8 = 2;
+
+impl Record for Rustls {
+    fn build(level: Level, desc: codec::AlertDescription) -> Vec<u8> {
+        let level = match level {
+            Level::Warning => ALERT_LEVEL_WARNING,
+            Level::Fatal => ALERT_LEVEL_FATAL
+        };
+
+        vec![level, desc.into()]
+    }
+
+    fn check(buf: &[u8]) -> io::Result<Option<(Level, codec::AlertDescription)>> {
+        if buf.len() < TLS_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Unable to decode"));
+        }
+
+        match (ContentType::from(buf[0]), &buf[TLS_HEADER_SIZE..]) {
+            (ContentType::Alert, &[level, desc]) => {
+                let level = match level {
+                    ALERT_LEVEL_FATAL => Level::Fatal,
+                    _ => Level::Warning
+                };
+
+                Ok(Some((level, desc.into())))
+            },
+            (ContentType::Alert, _) =>
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Unable to decode")),
+            (ContentType::ApplicationData, _) =>
+                unreachable!("Should not be ApplicationData"),
+            _ => Ok(None)
+        }
+    }
+}
+
+/// What the handshake negotiated, kept after the session is consumed.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    version: ProtocolVersion,
+    suite: SupportedCipherSuite,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Option<Vec<CertificateDer<'static>>>,
+    server_name: Option<String>,
+    exports: Vec<(Vec<u8>, Option<Vec<u8>>, Vec<u8>)>,
+    server_certificate: Option<CertificateDer<'static>>,
+    exporter_binding: Option<Vec<u8>>,
+    exporter_secret: Option<ExporterSecret>,
+    early_data: Option<Vec<u8>>
+}
+
+impl Handshake {
+    fn new(session: &Connection, exports: &[(&[u8], Option<&[u8]>, usize)])
+        -> io::Result<Handshake>
+    {
+        let (version, suite) = match (session.protocol_version(), session.negotiated_cipher_suite()) {
+            (Some(version), Some(suite)) => (version, suite),
+            _ => return Err(KtlsErrorKind::HandshakeIncomplete.into_error())
+        };
+
+        let (server_name, server_certificate) = match session {
+            Connection::Server(session) => (session.server_name().map(String::from), None),
+            Connection::Client(session) => (
+                None,
+                session.peer_certificates().and_then(|certs| certs.first().cloned())
+            )
+        };
+
+        let exports = exports.iter()
+            .map(|&(label, context, len)| {
+                let output = session.export_keying_material(vec![0; len], label, context)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                Ok((label.to_vec(), context.map(Vec::from), output))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let exporter_binding = if version == ProtocolVersion::TLSv1_3 {
+            let output = session.export_keying_material(vec![0; EXPORTER_LEN], EXPORTER_LABEL, None)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            Some(output)
+        } else {
+            None
+        };
+
+        Ok(Handshake {
+            version, suite, server_name, exports, server_certificate, exporter_binding,
+            exporter_secret: None,
+            early_data: None,
+            alpn_protocol: session.alpn_protocol().map(Vec::from),
+            peer_certificates: session.peer_certificates().map(Vec::from)
+        })
+    }
+
+    /// The negotiated protocol version.
+    #[inline]
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// The negotiated cipher suite.
+    #[inline]
+    pub fn cipher_suite(&self) -> SupportedCipherSuite {
+        self.suite
+    }
+
+    /// The protocol agreed on through ALPN, if any.
+    #[inline]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_ref().map(Vec::as_slice)
+    }
+
+    /// The certificate chain the peer presented, leaf first.
+    ///
+    /// `None` if the peer did not authenticate, as with a client under
+    /// a server config that has no client auth.
+    #[inline]
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.peer_certificates.as_ref().map(Vec::as_slice)
+    }
+
+    /// The SNI the client sent. Only known on the server side.
+    #[inline]
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_ref().map(String::as_str)
+    }
+
+    /// Data the client sent as TLS 1.3 0-RTT, if the server accepted any.
+    ///
+    /// 0-RTT data can be replayed by an attacker, so only act on it where that
+    /// is harmless, as with idempotent requests. It is only given out here,
+    /// reads start with what was sent after the handshake.
+    #[inline]
+    pub fn early_data(&self) -> Option<&[u8]> {
+        self.early_data.as_ref().map(Vec::as_slice)
+    }
+
+    /// Keying material as in RFC 5705 and RFC 8446 section 7.5.
+    ///
+    /// On TLS 1.3, any material can be derived once `set_exporter_log` was called, which
+    /// `connect`, `accept` and `KtlsListener` do. Otherwise rustls does not give out the
+    /// secrets behind the exporter, so only material asked for at install is available,
+    /// through `with_exports` or the `exports` of the helpers that install for you.
+    /// Anything else fails with `InvalidInput`.
+    pub fn export_keying_material(&self, label: &[u8], context: Option<&[u8]>, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        let exported = self.exports.iter()
+            .find(|(l, c, output)| l.as_slice() == label
+                && c.as_ref().map(Vec::as_slice) == context
+                && output.len() == len);
+
+        match (exported, &self.exporter_secret) {
+            (Some((_, _, output)), _) => Ok(output.clone()),
+            (None, Some(secret)) => secret.export(self.suite, label, context, len),
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keying material was not exported at install"
+            ))
+        }
+    }
+
+    /// Derive keying material on demand from the exporter secret `log` kept,
+    /// for a TLS 1.3 session made with a config from `ExporterLog`.
+    pub fn set_exporter_log(&mut self, log: &ExporterLog) {
+        self.exporter_secret = log.take();
+    }
+
+    /// Set the certificate the server sent, for `tls-server-end-point` on the server side.
+    ///
+    /// rustls does not say which certificate it picked, so the server has to tell.
+    /// The client takes the certificate from the handshake.
+    #[inline]
+    pub fn set_server_certificate(&mut self, cert: CertificateDer<'static>) {
+        self.server_certificate = Some(cert);
+    }
+
+    /// Channel binding data of the connection, for SASL `-PLUS` mechanisms.
+    pub fn channel_binding(&self, kind: ChannelBinding) -> io::Result<Vec<u8>> {
+        match kind {
+            ChannelBinding::TlsServerEndPoint => match self.server_certificate {
+                Some(ref cert) => binding::server_end_point(cert.as_ref()),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "server certificate is not known"))
+            },
+            ChannelBinding::TlsExporter => self.exporter_binding.clone()
+                .ok_or_else(|| KtlsErrorKind::Unsupported.into_error())
+        }
+    }
+}
+
+/// Install kTLS on `io` with the keys of a finished `session`.
+///
+/// The session has to come from a config with `enable_secret_extraction` set,
+/// and is consumed, since rustls can not be used once the kernel has the keys.
+/// Keying material is exported for each `(label, context, len)` of `exports`
+/// before that, to be read back through `Handshake::export_keying_material`.
+///
+/// Records rustls still has queued are written to `io` first, so the kernel
+/// starts from the sequence numbers the peer expects. If `io` can not take
+/// all of them now, this fails with `PendingWrite`.
+///
+/// On failure `io` is handed back, and so is the session if it was not given up
+/// yet, so that a `HandshakeIncomplete` or `PendingWrite` can be retried later.
+///
+/// Complete records rustls already read from the socket are decrypted here,
+/// and their plaintext is returned by the first reads of the new stream.
+/// A partial record left in rustls can not be handed to the kernel. Drive the
+/// handshake with a `RecordReader`, as the helpers here do, so there is none.
+/// Otherwise this fails with `PartialRecord` when the data waiting on the socket
+/// does not start a record, before the session is consumed.
+pub fn install<IO, S>(mut io: IO, session: S, exports: &[(&[u8], Option<&[u8]>, usize)])
+    -> Result<(InnerStream<IO, Rustls>, Handshake), Error<(IO, Option<Connection>)>>
+where
+    IO: Read + Write + AsRawFd,
+    S: Into<Connection>
+{
+    let mut session = session.into();
+
+    let (handshake, buffered, read_closed) = match prepare(&mut session, &mut io, exports) {
+        Ok(prepared) => prepared,
+        Err(error) => return Err(Error { error, inner: (io, Some(session)) })
+    };
+
+    let (tx, rx) = match extract_secrets(session, handshake.version) {
+        Ok(secrets) => secrets,
+        Err(error) => return Err(Error { error, inner: (io, None) })
+    };
+
+    let mut kstream = InnerStream::new(io, &tx, &rx)
+        .map_err(|err| Error { error: err.error, inner: (err.inner, None) })?;
+    kstream.set_buffered_plaintext(buffered);
+
+    if read_closed {
+        if let Err(error) = kstream.shutdown(Shutdown::Read) {
+            return Err(Error { error, inner: (kstream.into_inner(), None) });
+        }
+    }
+
+    Ok((kstream, handshake))
+}
+
+/// Take everything install needs from `session`, short of its secrets.
+fn prepare<IO>(session: &mut Connection, io: &mut IO, exports: &[(&[u8], Option<&[u8]>, usize)])
+    -> io::Result<(Handshake, Vec<u8>, bool)>
+where IO: Write + AsRawFd
+{
+    if session.is_handshaking() {
+        return Err(KtlsErrorKind::HandshakeIncomplete.into_error());
+    }
+
+    record::check_boundary(io)?;
+
+    let mut handshake = Handshake::new(session, exports)?;
+    flush_session(session, io)?;
+    let (buffered, read_closed) = take_plaintext(session)?;
+    handshake.early_data = take_early_data(session)?;
+
+    Ok((handshake, buffered, read_closed))
+}
+
+/// Whether the negotiated suite is one the kernel knows,
+/// which can be told without giving up the session.
+pub fn is_supported(session: &Connection) -> bool {
+    probe_info(session).is_some()
+}
+
+/// Whether the running kernel takes keys of the negotiated suite, tried once for each
+/// suite with zero keys on a loopback connection. See `ktls::probe`.
+pub fn kernel_supports(session: &Connection) -> io::Result<bool> {
+    match probe_info(session) {
+        Some(info) => crate::probe(&info),
+        None => Ok(false)
+    }
+}
+
+/// Kernel parameters of the negotiated suite, with zero keys.
+fn probe_info(session: &Connection) -> Option<CryptoInfo> {
+    let version = match session.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => TlsVersion::Tls12,
+        Some(ProtocolVersion::TLSv1_3) => TlsVersion::Tls13,
+        _ => return None
+    };
+
+    match session.negotiated_cipher_suite().map(|suite| suite.suite()) {
+        Some(CipherSuite::TLS13_AES_128_GCM_SHA256)
+            | Some(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256)
+            | Some(CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256) =>
+            Some(CryptoInfo::aes_gcm_128(version, &[0; 16], &[0; 12], 0)),
+        Some(CipherSuite::TLS13_AES_256_GCM_SHA384)
+            | Some(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384)
+            | Some(CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384) =>
+            Some(CryptoInfo::aes_gcm_256(version, &[0; 32], &[0; 12], 0)),
+        Some(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+            | Some(CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256)
+            | Some(CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256) =>
+            Some(CryptoInfo::chacha20_poly1305(version, &[0; 32], &[0; 12], 0)),
+        _ => None
+    }
+}
+
+/// Write out every record rustls has queued.
+pub fn flush_session<IO: Write>(session: &mut Connection, io: &mut IO) -> io::Result<()> {
+    while session.wants_write() {
+        match session.write_tls(io) {
+            Ok(0) => return Err(KtlsErrorKind::PendingWrite.with_source(io::ErrorKind::WriteZero.into())),
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(KtlsErrorKind::PendingWrite.with_source(err))
+        }
+    }
+
+    io.flush()
+}
+
+/// Decrypt what rustls has buffered and take out the plaintext,
+/// along with whether the peer already sent close_notify.
+fn take_plaintext(session: &mut Connection) -> io::Result<(Vec<u8>, bool)> {
+    let state = session.process_new_packets()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let mut buffered = vec![0; state.plaintext_bytes_to_read()];
+    session.reader().read_exact(&mut buffered)?;
+
+    Ok((buffered, state.peer_has_closed()))
+}
+
+/// Take the 0-RTT data a server session accepted.
+///
+/// It came under the early traffic keys, which have no bearing on the sequence
+/// numbers of the application traffic keys the kernel gets.
+fn take_early_data(session: &mut Connection) -> io::Result<Option<Vec<u8>>> {
+    let mut early_data = match session {
+        Connection::Server(session) => match session.early_data() {
+            Some(early_data) => early_data,
+            None => return Ok(None)
+        },
+        Connection::Client(_) => return Ok(None)
+    };
+
+    let mut buf = Vec::new();
+    early_data.read_to_end(&mut buf)?;
+    Ok(Some(buf))
+}
+
+fn extract_secrets(session: Connection, version: ProtocolVersion)
+    -> io::Result<(CryptoInfo, CryptoInfo)>
+{
+    let version = match version {
+        ProtocolVersion::TLSv1_2 => TlsVersion::Tls12,
+        ProtocolVersion::TLSv1_3 => TlsVersion::Tls13,
+        _ => return Err(KtlsErrorKind::Unsupported.into_error())
+    };
+
+    let secrets = session.dangerous_extract_secrets()
+        .map_err(|err| KtlsErrorKind::Unsupported.with_source(io::Error::new(io::ErrorKind::Other, err)))?;
+
+    Ok((crypto_info(version, secrets.tx)?, crypto_info(version, secrets.rx)?))
+}
+
+/// Turn one direction of the secrets rustls extracted into kernel parameters.
+fn crypto_info(version: TlsVersion, (seq, secrets): (u64, ConnectionTrafficSecrets))
+    -> io::Result<CryptoInfo>
+{
+    match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, iv } =>
+            Ok(CryptoInfo::aes_gcm_128(version, key.as_ref(), iv.as_ref(), seq)),
+        ConnectionTrafficSecrets::Aes256Gcm { key, iv } =>
+            Ok(CryptoInfo::aes_gcm_256(version, key.as_ref(), iv.as_ref(), seq)),
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } =>
+            Ok(CryptoInfo::chacha20_poly1305(version, key.as_ref(), iv.as_ref(), seq)),
+        _ => Err(KtlsErrorKind::Unsupported.into_error())
+    }
+}
+
+/// A kTLS stream set up from rustls, along with what its handshake negotiated.
+#[derive(Debug)]
+pub struct KtlsStream<IO> {
+    io: InnerStream<IO, Rustls>,
+    handshake: Handshake
+}
+
+impl<IO> KtlsStream<IO> {
+    #[inline]
+    pub fn get_ref(&self) -> &InnerStream<IO, Rustls> {
+        &self.io
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut InnerStream<IO, Rustls> {
+        &mut self.io
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> InnerStream<IO, Rustls> {
+        self.io
+    }
+
+    #[inline]
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    #[inline]
+    pub fn handshake_mut(&mut self) -> &mut Handshake {
+        &mut self.handshake
+    }
+
+    /// The negotiated protocol version.
+    #[inline]
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.handshake.protocol_version()
+    }
+
+    /// The negotiated cipher suite.
+    #[inline]
+    pub fn cipher_suite(&self) -> SupportedCipherSuite {
+        self.handshake.cipher_suite()
+    }
+
+    /// The protocol agreed on through ALPN, if any.
+    #[inline]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.handshake.alpn_protocol()
+    }
+
+    /// The certificate chain the peer presented, leaf first.
+    #[inline]
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.handshake.peer_certificates()
+    }
+
+    /// The SNI the client sent. Only known on the server side.
+    #[inline]
+    pub fn server_name(&self) -> Option<&str> {
+        self.handshake.server_name()
+    }
+
+    /// Data the client sent as TLS 1.3 0-RTT, which can be replayed.
+    ///
+    /// It is only given out here, reads start with what was sent after the handshake.
+    #[inline]
+    pub fn early_data(&self) -> Option<&[u8]> {
+        self.handshake.early_data()
+    }
+
+    /// Keying material as in RFC 5705 and RFC 8446 section 7.5.
+    ///
+    /// See `Handshake::export_keying_material` for which material is available.
+    #[inline]
+    pub fn export_keying_material(&self, label: &[u8], context: Option<&[u8]>, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        self.handshake.export_keying_material(label, context, len)
+    }
+
+    /// Set the certificate the server sent, for `tls-server-end-point` on the server side.
+    #[inline]
+    pub fn set_server_certificate(&mut self, cert: CertificateDer<'static>) {
+        self.handshake.set_server_certificate(cert)
+    }
+
+    /// Channel binding data of the connection, for SASL `-PLUS` mechanisms.
+    #[inline]
+    pub fn channel_binding(&self, kind: ChannelBinding) -> io::Result<Vec<u8>> {
+        self.handshake.channel_binding(kind)
+    }
+
+    #[inline]
+    pub fn last_alert(&self) -> Option<(Level, AlertDescription)> {
+        self.io.last_alert()
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.io.state()
+    }
+
+    #[inline]
+    pub fn set_renegotiation(&mut self, renegotiation: Renegotiation) {
+        self.io.set_renegotiation(renegotiation)
+    }
+
+    #[inline]
+    pub fn set_ticket_handler<F>(&mut self, f: F)
+    where F: FnMut(SessionTicket) + Send + 'static
+    {
+        self.io.set_ticket_handler(f)
+    }
+}
+
+impl<IO> KtlsStream<IO>
+where
+    IO: Read + Write + AsRawFd,
+{
+    /// Install kTLS on `io` with the keys of a finished `session`. See `install`.
+    pub fn new<S>(io: IO, session: S) -> Result<Self, Error<(IO, Option<Connection>)>>
+    where S: Into<Connection>
+    {
+        KtlsStream::with_exports(io, session, &[])
+    }
+
+    /// Like `new`, and also export keying material for each `(label, context, len)`.
+    pub fn with_exports<S>(io: IO, session: S, exports: &[(&[u8], Option<&[u8]>, usize)])
+        -> Result<Self, Error<(IO, Option<Connection>)>>
+        where S: Into<Connection>
+    {
+        let (io, handshake) = install(io, session, exports)?;
+        Ok(KtlsStream { io, handshake })
+    }
+
+    /// Move to a new TLS 1.3 traffic key for sending, on a stream from `connect_kernel`
+    /// or `accept_kernel`. See `ktls::KtlsStream::send_key_update`.
+    #[inline]
+    pub fn send_key_update(&mut self, request_update: bool) -> io::Result<()> {
+        self.io.send_key_update(request_update)
+    }
+}
+
+/// Run a blocking client handshake with `server_name` on `io`, then install kTLS.
+pub fn connect<IO>(io: IO, config: Arc<ClientConfig>, server_name: ServerName<'static>)
+    -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    connect_with_exports(io, config, server_name, &[])
+}
+
+/// Like `connect`, and also export keying material for each `(label, context, len)`.
+pub fn connect_with_exports<IO>(
+    mut io: IO,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    exports: &[(&[u8], Option<&[u8]>, usize)]
+) -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    let (config, log) = ExporterLog::client(&config);
+    let mut session = ClientConnection::new(config, server_name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .into();
+    RecordReader::new().complete_handshake(&mut session, &mut io)?;
+
+    let mut stream = KtlsStream::with_exports(io, session, exports).map_err(|err| err.error)?;
+    stream.handshake_mut().set_exporter_log(&log);
+    Ok(stream)
+}
+
+/// Run a blocking server handshake on `io`, then install kTLS.
+pub fn accept<IO>(io: IO, config: Arc<ServerConfig>) -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    accept_with_exports(io, config, &[])
+}
+
+/// Like `accept`, and also export keying material for each `(label, context, len)`.
+pub fn accept_with_exports<IO>(
+    mut io: IO,
+    config: Arc<ServerConfig>,
+    exports: &[(&[u8], Option<&[u8]>, usize)]
+) -> io::Result<KtlsStream<IO>>
+where IO: Read + Write + AsRawFd
+{
+    let (config, log) = ExporterLog::server(&config);
+    let mut session = ServerConnection::new(config)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .into();
+    RecordReader::new().complete_handshake(&mut session, &mut io)?;
+
+    let mut stream = KtlsStream::with_exports(io, session, exports).map_err(|err| err.error)?;
+    stream.handshake_mut().set_exporter_log(&log);
+    Ok(stream)
+}
+
+impl<IO> Read for KtlsStream<IO>
+where
+    IO: Read + Write + AsRawFd,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<IO: Write> Write for KtlsStream<IO> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<IO: AsRawFd> AsRawFd for KtlsStream<IO> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::{ self, Read, Write };
+use std::os::unix::io::AsRawFd;
+use ::rustls::Connection;
+use crate::{ sys, KtlsErrorKind };
+use crate::sys::{ TLS_HEADER_SIZE, TLS_MAX_PAYLOAD_SIZE };
+
+/// Largest record payload either version allows, with the room TLS 1.2 leaves
+/// for compression and padding.
+const MAX_RECORD_SIZE: usize = TLS_MAX_PAYLOAD_SIZE + 2048;
+
+
+/// Hands records to rustls one whole record at a time.
+///
+/// `Connection::read_tls` takes whatever the socket has, so when the handshake ends
+/// rustls may hold the start of the next record, whose rest the kernel then fails
+/// to authenticate. Reading the header first and then exactly the record leaves the
+/// socket on a record boundary. Progress is kept across `WouldBlock`.
+#[derive(Debug, Default)]
+pub struct RecordReader {
+    buf: Vec<u8>
+}
+
+impl RecordReader {
+    pub fn new() -> RecordReader {
+        RecordReader::default()
+    }
+
+    /// Read the rest of the current record from `io` and pass it to `session`.
+    ///
+    /// Returns the size of the record, or `0` at end of stream.
+    pub fn read_tls<IO: Read>(&mut self, session: &mut Connection, io: &mut IO) -> io::Result<usize> {
+        if self.fill(io)? == 0 {
+            return Ok(0);
+        }
+
+        // rustls may take a large record in several reads
+        let mut record = &self.buf[..];
+        while !record.is_empty() {
+            if session.read_tls(&mut record)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "rustls took no more of the record"));
+            }
+        }
+
+        let n = self.buf.len();
+        self.buf.clear();
+        Ok(n)
+    }
+
+    /// Read the rest of the current record from `io` and append it to `incoming`,
+    /// for the unbuffered API of rustls.
+    ///
+    /// Returns the size of the record, or `0` at end of stream.
+    pub(super) fn read_record<IO: Read>(&mut self, incoming: &mut Vec<u8>, io: &mut IO) -> io::Result<usize> {
+        if self.fill(io)? == 0 {
+            return Ok(0);
+        }
+
+        incoming.extend_from_slice(&self.buf);
+        let n = self.buf.len();
+        self.buf.clear();
+        Ok(n)
+    }
+
+    /// Read until `buf` holds the whole current record.
+    ///
+    /// Returns the size of the record, or `0` at end of stream.
+    fn fill<IO: Read>(&mut self, io: &mut IO) -> io::Result<usize> {
+        loop {
+            let want = self.want()?;
+
+            if self.buf.len() == want {
+                break
+            }
+
+            let start = self.buf.len();
+            self.buf.resize(want, 0);
+
+            match io.read(&mut self.buf[start..]) {
+                Ok(0) => {
+                    self.buf.truncate(start);
+                    return Ok(0);
+                },
+                Ok(n) => self.buf.truncate(start + n),
+                Err(err) => {
+                    self.buf.truncate(start);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(self.buf.len())
+    }
+
+    /// Drive the handshake of `session` on `io` until it finishes.
+    ///
+    /// On a nonblocking `io` this fails with `WouldBlock`, and can be called again.
+    pub fn complete_handshake<IO>(&mut self, session: &mut Connection, io: &mut IO) -> io::Result<()>
+    where IO: Read + Write
+    {
+        loop {
+            while session.wants_write() {
+                if session.write_tls(io)? == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+            }
+
+            if !session.is_handshaking() {
+                return io.flush();
+            }
+
+            if self.read_tls(session, io)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            if let Err(err) = session.process_new_packets() {
+                // best effort to send the alert rustls queued
+                let _ = session.write_tls(io);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+
+    /// How much of the current record there is to have in `buf`.
+    fn want(&self) -> io::Result<usize> {
+        if self.buf.len() < TLS_HEADER_SIZE {
+            return Ok(TLS_HEADER_SIZE);
+        }
+
+        let len = usize::from(u16::from_be_bytes([self.buf[3], self.buf[4]]));
+        if len > MAX_RECORD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "record is too large"));
+        }
+
+        Ok(TLS_HEADER_SIZE + len)
+    }
+}
+
+/// Fail with `PartialRecord` if what already waits on `io` does not start a record,
+/// which means rustls took the start of it.
+///
+/// Data that has not arrived yet can not be checked.
+pub(super) fn check_boundary<IO: AsRawFd>(io: &mut IO) -> io::Result<()> {
+    let mut header = [0; TLS_HEADER_SIZE];
+
+    let n = match unsafe { sys::recv(io, &mut header, libc::MSG_PEEK | libc::MSG_DONTWAIT) } {
+        Ok(n) => n,
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+        Err(err) => return Err(KtlsErrorKind::from_install(err))
+    };
+
+    if is_header(&header[..n]) {
+        Ok(())
+    } else {
+        Err(KtlsErrorKind::PartialRecord.into_error())
+    }
+}
+
+/// Whether `buf` can be the start of a record header:
+/// a known content type, a 3.x version and a length in bounds.
+fn is_header(buf: &[u8]) -> bool {
+    match *buf {
+        [typ, ..] if !(20..=24).contains(&typ) => false,
+        [_, major, ..] if major != 3 => false,
+        [_, _, minor, ..] if !(1..=4).contains(&minor) => false,
+        [_, _, _, hi, lo] => usize::from(u16::from_be_bytes([hi, lo])) <= MAX_RECORD_SIZE,
+        _ => true
+    }
+}
@@ -0,0 +1,68 @@
+use std::io;
+use crate::KtlsErrorKind;
+
+
+/// Which directions of a `KtlsStream` are still usable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Open,
+    /// The peer sent close_notify.
+    ReadClosed,
+    /// We sent close_notify.
+    WriteClosed,
+    Closed,
+    Failed(KtlsErrorKind)
+}
+
+impl State {
+    #[inline]
+    pub fn can_read(self) -> bool {
+        match self {
+            State::Open | State::WriteClosed => true,
+            _ => false
+        }
+    }
+
+    #[inline]
+    pub fn can_write(self) -> bool {
+        match self {
+            State::Open | State::ReadClosed => true,
+            _ => false
+        }
+    }
+
+    pub(crate) fn close_read(self) -> State {
+        match self {
+            State::Open => State::ReadClosed,
+            State::WriteClosed => State::Closed,
+            state => state
+        }
+    }
+
+    pub(crate) fn close_write(self) -> State {
+        match self {
+            State::Open => State::WriteClosed,
+            State::ReadClosed => State::Closed,
+            state => state
+        }
+    }
+
+    /// Fail the way a write would in this state.
+    pub fn check_write(self) -> io::Result<()> {
+        match self {
+            State::Failed(kind) => Err(kind.into_error()),
+            State::WriteClosed | State::Closed =>
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "tls stream is closed for writing")),
+            State::Open | State::ReadClosed => Ok(())
+        }
+    }
+}
+
+/// How a graceful close ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseStatus {
+    /// The peer answered with close_notify.
+    Clean,
+    /// The connection ended, or the wait timed out, without close_notify from the peer.
+    Truncated
+}
@@ -1,7 +1,9 @@
 #[allow(non_camel_case_types)]
 mod tls;
 
-use std::{ mem, io, ptr };
+use std::{ mem, io, ptr, cmp };
+use std::time::Duration;
+use std::net::Shutdown;
 use std::os::unix::io::AsRawFd;
 use byteorder::{ ByteOrder, NetworkEndian };
 pub use self::tls::*;
@@ -11,6 +13,10 @@ pub const TCP_ULP: libc::c_int = 31;
 pub const SOL_TCP: libc::c_int = 6;
 pub const SOL_TLS: libc::c_int = 282;
 pub const TLS_1_2_VERSION: libc::c_uint = 0x0303;
+pub const TLS_1_3_VERSION: libc::c_uint = 0x0304;
+pub const TLS_HEADER_SIZE: usize = 5;
+pub const TLS_MAX_PAYLOAD_SIZE: usize = 1 << 14;
+pub const TLS_RECORD_TYPE_DATA: u8 = 0x17;
 
 const CMSG_LEN: usize = mem::size_of::<u8>();
 
@@ -37,28 +43,60 @@ macro_rules! cmsg {
     }
 }
 
-pub unsafe fn start<Fd: AsRawFd>(
-    socket: &mut Fd,
-    tx: &tls12_crypto_info_aes_gcm_128,
-    rx: &tls12_crypto_info_aes_gcm_128
-) -> io::Result<()> {
-    const INFO_SIZE: usize = mem::size_of::<tls12_crypto_info_aes_gcm_128>();
+pub unsafe fn start<Fd: AsRawFd>(socket: &mut Fd, tx: &CryptoInfo, rx: &CryptoInfo)
+    -> io::Result<()>
+{
+    attach_ulp(socket)?;
+    set_crypto_info(socket, TLS_TX, tx)?;
+    set_crypto_info(socket, TLS_RX, rx)
+}
+
+/// Replace the key of one direction after a TLS 1.3 KeyUpdate, which takes Linux 6.13 or later.
+pub unsafe fn update_tx<Fd: AsRawFd>(socket: &mut Fd, tx: &CryptoInfo) -> io::Result<()> {
+    set_crypto_info(socket, TLS_TX, tx)
+}
+
+/// See `update_tx`.
+pub unsafe fn update_rx<Fd: AsRawFd>(socket: &mut Fd, rx: &CryptoInfo) -> io::Result<()> {
+    set_crypto_info(socket, TLS_RX, rx)
+}
+
+unsafe fn set_crypto_info<Fd: AsRawFd>(socket: &mut Fd, direction: u32, info: &CryptoInfo)
+    -> io::Result<()>
+{
+    let (ptr, size) = info.as_raw();
 
+    if libc::setsockopt(socket.as_raw_fd(), SOL_TLS, direction as _, ptr, size as _) < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Attach the `tls` ULP, if it is not attached yet.
+pub unsafe fn attach_ulp<Fd: AsRawFd>(socket: &mut Fd) -> io::Result<()> {
     let socket = socket.as_raw_fd();
 
-    if libc::setsockopt(socket, SOL_TCP, TCP_ULP, b"tls\0".as_ptr() as _, 4) < 0 {
-        return Err(io::Error::last_os_error());
+    if libc::setsockopt(socket, SOL_TCP, TCP_ULP, b"tls\0".as_ptr() as _, 4) == 0 {
+        return Ok(());
     }
 
-    if libc::setsockopt(socket, SOL_TLS, TLS_TX as _, tx as *const _ as _, INFO_SIZE as _) < 0 {
-        return Err(io::Error::last_os_error());
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EEXIST) {
+        return Err(err);
     }
 
-    if libc::setsockopt(socket, SOL_TLS, TLS_RX as _, rx as *const _ as _, INFO_SIZE as _) < 0 {
+    let mut name = [0u8; 16];
+    let mut len = name.len() as libc::socklen_t;
+    if libc::getsockopt(socket, SOL_TCP, TCP_ULP, name.as_mut_ptr() as _, &mut len) < 0 {
         return Err(io::Error::last_os_error());
     }
 
-    Ok(())
+    if name.starts_with(b"tls\0") {
+        Ok(())
+    } else {
+        Err(err)
+    }
 }
 
 pub unsafe fn send_ctrl_message<Fd: AsRawFd>(socket: &mut Fd, record_type: u8, data: &[u8])
@@ -88,39 +126,124 @@ pub unsafe fn send_ctrl_message<Fd: AsRawFd>(socket: &mut Fd, record_type: u8, d
     }
 }
 
-pub unsafe fn recv_ctrl_message<Fd: AsRawFd>(socket: &mut Fd, record: &mut [u8]) -> io::Result<usize> {
-    const HEADER_LENGTH: usize = 5;
+pub unsafe fn shutdown<Fd: AsRawFd>(socket: &mut Fd, how: Shutdown) -> io::Result<()> {
+    let how = match how {
+        Shutdown::Read => libc::SHUT_RD,
+        Shutdown::Write => libc::SHUT_WR,
+        Shutdown::Both => libc::SHUT_RDWR
+    };
+
+    if libc::shutdown(socket.as_raw_fd(), how) < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
 
+/// Wait until the socket is readable, returning `false` if `timeout` passes first.
+pub unsafe fn poll_read<Fd: AsRawFd>(socket: &mut Fd, timeout: Option<Duration>) -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0
+    };
+    let timeout = match timeout {
+        Some(timeout) => cmp::min(timeout.as_millis(), libc::c_int::max_value() as u128) as libc::c_int,
+        None => -1
+    };
+
+    match libc::poll(&mut fds, 1, timeout) {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n > 0)
+    }
+}
+
+pub unsafe fn send<Fd: AsRawFd>(socket: &mut Fd, data: &[u8], flags: libc::c_int)
+    -> io::Result<usize>
+{
+    match libc::send(socket.as_raw_fd(), data.as_ptr() as *const _, data.len(), flags) {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as _)
+    }
+}
+
+pub unsafe fn recv<Fd: AsRawFd>(socket: &mut Fd, data: &mut [u8], flags: libc::c_int)
+    -> io::Result<usize>
+{
+    match libc::recv(socket.as_raw_fd(), data.as_mut_ptr() as *mut _, data.len(), flags) {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as _)
+    }
+}
+
+pub unsafe fn sendfile<Fd: AsRawFd, F: AsRawFd>(socket: &mut Fd, file: &F, offset: &mut u64, count: usize)
+    -> io::Result<usize>
+{
+    let mut off = *offset as libc::off_t;
+
+    match libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut off, count) {
+        -1 => Err(io::Error::last_os_error()),
+        n => {
+            *offset = off as u64;
+            Ok(n as _)
+        }
+    }
+}
+
+/// Receive from a kTLS socket with room for the record type control message.
+///
+/// Returns the record type reported by the kernel, or `None` if no record was read.
+pub unsafe fn recv_message<Fd: AsRawFd>(socket: &mut Fd, data: &mut [u8], flags: libc::c_int)
+    -> io::Result<(Option<u8>, usize)>
+{
     let mut msg: libc::msghdr = mem::zeroed();
     let mut buf = [0; cmsg!(space CMSG_LEN)];
-    let mut msg_iov: libc::iovec = mem::uninitialized();
+    let mut msg_iov: libc::iovec = mem::zeroed();
 
     msg.msg_control = buf.as_mut_ptr() as *mut _;
     msg.msg_controllen = mem::size_of_val(&buf);
 
-    msg_iov.iov_base = record.as_mut_ptr().add(HEADER_LENGTH) as *mut _;
-    msg_iov.iov_len = (record.len() - HEADER_LENGTH) as _;
+    msg_iov.iov_base = data.as_mut_ptr() as *mut _;
+    msg_iov.iov_len = data.len() as _;
 
     msg.msg_iov = &mut msg_iov;
     msg.msg_iovlen = 1;
 
-    match libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) {
+    match libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) {
         -1 => Err(io::Error::last_os_error()),
         n => {
             let cmsg: *mut libc::cmsghdr = cmsg!(firsthdr &msg);
-            if (*cmsg).cmsg_level == SOL_TLS && (*cmsg).cmsg_type == TLS_GET_RECORD_TYPE as _ {
-                record[0] = *cmsg!(data cmsg);
-                record[1] = TLS_1_2_VERSION_MAJOR as _;
-                record[2] = TLS_1_2_VERSION_MINOR as _;
-                NetworkEndian::write_u16(&mut record[3..][..2], n as u16);
-                Ok(n as usize + HEADER_LENGTH)
+            let record_type = if !cmsg.is_null()
+                && (*cmsg).cmsg_level == SOL_TLS
+                && (*cmsg).cmsg_type == TLS_GET_RECORD_TYPE as _
+            {
+                Some(*cmsg!(data cmsg))
             } else {
-                Err(io::Error::new(io::ErrorKind::Other, "Buffer contains application data"))
-            }
+                None
+            };
+
+            Ok((record_type, n as usize))
         }
     }
 }
 
+pub unsafe fn recv_ctrl_message<Fd: AsRawFd>(socket: &mut Fd, record: &mut [u8]) -> io::Result<usize> {
+    match recv_message(socket, &mut record[TLS_HEADER_SIZE..], 0)? {
+        (Some(record_type), n) if record_type != TLS_RECORD_TYPE_DATA => {
+            write_header(record_type, n, record);
+            Ok(n + TLS_HEADER_SIZE)
+        },
+        _ => Err(io::Error::new(io::ErrorKind::Other, "Buffer contains application data"))
+    }
+}
+
+/// Write a TLS 1.2 record header for a payload of `len` bytes.
+pub fn write_header(record_type: u8, len: usize, record: &mut [u8]) {
+    record[0] = record_type;
+    record[1] = TLS_1_2_VERSION_MAJOR as _;
+    record[2] = TLS_1_2_VERSION_MINOR as _;
+    NetworkEndian::write_u16(&mut record[3..][..2], len as u16);
+}
 
 impl Default for tls_crypto_info {
     fn default() -> Self {
@@ -131,30 +254,85 @@ impl Default for tls_crypto_info {
     }
 }
 
-impl tls12_crypto_info_aes_gcm_128 {
-    pub fn from_secrets(is_client: bool, secrets: &[u8], (read_seq, write_seq): (u64, u64)) -> (Self, Self) {
-        let (mut tx, mut rx) = (tls12_crypto_info_aes_gcm_128::default(), tls12_crypto_info_aes_gcm_128::default());
+/// The protocol version a connection negotiated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13
+}
 
-        let (client_key, remaining) = secrets.split_at(TLS_CIPHER_AES_GCM_128_KEY_SIZE as _);
-        let (server_key, remaining) = remaining.split_at(TLS_CIPHER_AES_GCM_128_KEY_SIZE as _);
-        let (client_iv, remaining) = remaining.split_at(TLS_CIPHER_AES_GCM_128_SALT_SIZE as _);
-        let (server_iv, remaining) = remaining.split_at(TLS_CIPHER_AES_GCM_128_SALT_SIZE as _);
-        let (nonce, _) = remaining.split_at(TLS_CIPHER_AES_GCM_128_IV_SIZE as _);
+impl TlsVersion {
+    fn info(self, cipher_type: u32) -> tls_crypto_info {
+        let version = match self {
+            TlsVersion::Tls12 => TLS_1_2_VERSION,
+            TlsVersion::Tls13 => TLS_1_3_VERSION
+        };
 
-        tx.key.copy_from_slice(client_key);
-        tx.salt.copy_from_slice(client_iv);
-        tx.iv.copy_from_slice(nonce);
-        NetworkEndian::write_u64(&mut tx.rec_seq, write_seq);
+        tls_crypto_info { version: version as _, cipher_type: cipher_type as _ }
+    }
+}
 
-        rx.key.copy_from_slice(server_key);
-        rx.salt.copy_from_slice(server_iv);
-        rx.iv.copy_from_slice(nonce);
-        NetworkEndian::write_u64(&mut rx.rec_seq, read_seq);
+/// Kernel parameters for one direction of a connection.
+///
+/// `iv` is the full 12 byte nonce the TLS library derived, before the record
+/// sequence number is mixed in. For AES-GCM the kernel takes it apart into salt
+/// and iv. Keys and nonces of the wrong length panic.
+#[derive(Debug, Clone, Copy)]
+pub enum CryptoInfo {
+    AesGcm128(tls12_crypto_info_aes_gcm_128),
+    AesGcm256(tls12_crypto_info_aes_gcm_256),
+    Chacha20Poly1305(tls12_crypto_info_chacha20_poly1305)
+}
 
-        if is_client {
-            (tx, rx)
-        } else {
-            (rx, tx)
+impl CryptoInfo {
+    pub fn aes_gcm_128(version: TlsVersion, key: &[u8], iv: &[u8], seq: u64) -> CryptoInfo {
+        let mut info = tls12_crypto_info_aes_gcm_128::default();
+        info.info = version.info(TLS_CIPHER_AES_GCM_128);
+        info.key.copy_from_slice(key);
+        info.salt.copy_from_slice(&iv[..TLS_CIPHER_AES_GCM_128_SALT_SIZE as usize]);
+        info.iv.copy_from_slice(&iv[TLS_CIPHER_AES_GCM_128_SALT_SIZE as usize..]);
+        NetworkEndian::write_u64(&mut info.rec_seq, seq);
+        CryptoInfo::AesGcm128(info)
+    }
+
+    pub fn aes_gcm_256(version: TlsVersion, key: &[u8], iv: &[u8], seq: u64) -> CryptoInfo {
+        let mut info = tls12_crypto_info_aes_gcm_256::default();
+        info.info = version.info(TLS_CIPHER_AES_GCM_256);
+        info.key.copy_from_slice(key);
+        info.salt.copy_from_slice(&iv[..TLS_CIPHER_AES_GCM_256_SALT_SIZE as usize]);
+        info.iv.copy_from_slice(&iv[TLS_CIPHER_AES_GCM_256_SALT_SIZE as usize..]);
+        NetworkEndian::write_u64(&mut info.rec_seq, seq);
+        CryptoInfo::AesGcm256(info)
+    }
+
+    pub fn chacha20_poly1305(version: TlsVersion, key: &[u8], iv: &[u8], seq: u64) -> CryptoInfo {
+        let mut info = tls12_crypto_info_chacha20_poly1305::default();
+        info.info = version.info(TLS_CIPHER_CHACHA20_POLY1305);
+        info.key.copy_from_slice(key);
+        info.iv.copy_from_slice(iv);
+        NetworkEndian::write_u64(&mut info.rec_seq, seq);
+        CryptoInfo::Chacha20Poly1305(info)
+    }
+
+    /// Which of the versions and ciphers this is, as an index from `0` to `5`.
+    pub(crate) fn slot(&self) -> usize {
+        let (info, cipher) = match self {
+            CryptoInfo::AesGcm128(info) => (info.info, 0),
+            CryptoInfo::AesGcm256(info) => (info.info, 1),
+            CryptoInfo::Chacha20Poly1305(info) => (info.info, 2)
+        };
+
+        match info.version as libc::c_uint {
+            TLS_1_3_VERSION => 3 + cipher,
+            _ => cipher
+        }
+    }
+
+    fn as_raw(&self) -> (*const libc::c_void, usize) {
+        match self {
+            CryptoInfo::AesGcm128(info) => (info as *const _ as _, mem::size_of_val(info)),
+            CryptoInfo::AesGcm256(info) => (info as *const _ as _, mem::size_of_val(info)),
+            CryptoInfo::Chacha20Poly1305(info) => (info as *const _ as _, mem::size_of_val(info))
         }
     }
 }
@@ -10,6 +10,20 @@ pub const TLS_CIPHER_AES_GCM_128_KEY_SIZE: u32 = 16;
 pub const TLS_CIPHER_AES_GCM_128_SALT_SIZE: u32 = 4;
 pub const TLS_CIPHER_AES_GCM_128_TAG_SIZE: u32 = 16;
 pub const TLS_CIPHER_AES_GCM_128_REC_SEQ_SIZE: u32 = 8;
+pub const TLS_1_3_VERSION_MAJOR: u32 = 3;
+pub const TLS_1_3_VERSION_MINOR: u32 = 4;
+pub const TLS_CIPHER_AES_GCM_256: u32 = 52;
+pub const TLS_CIPHER_AES_GCM_256_IV_SIZE: u32 = 8;
+pub const TLS_CIPHER_AES_GCM_256_KEY_SIZE: u32 = 32;
+pub const TLS_CIPHER_AES_GCM_256_SALT_SIZE: u32 = 4;
+pub const TLS_CIPHER_AES_GCM_256_TAG_SIZE: u32 = 16;
+pub const TLS_CIPHER_AES_GCM_256_REC_SEQ_SIZE: u32 = 8;
+pub const TLS_CIPHER_CHACHA20_POLY1305: u32 = 54;
+pub const TLS_CIPHER_CHACHA20_POLY1305_IV_SIZE: u32 = 12;
+pub const TLS_CIPHER_CHACHA20_POLY1305_KEY_SIZE: u32 = 32;
+pub const TLS_CIPHER_CHACHA20_POLY1305_SALT_SIZE: u32 = 0;
+pub const TLS_CIPHER_CHACHA20_POLY1305_TAG_SIZE: u32 = 16;
+pub const TLS_CIPHER_CHACHA20_POLY1305_REC_SEQ_SIZE: u32 = 8;
 pub const TLS_SET_RECORD_TYPE: u32 = 1;
 pub const TLS_GET_RECORD_TYPE: u32 = 2;
 pub type __u16 = libc::c_ushort;
@@ -134,3 +148,167 @@ fn bindgen_test_layout_tls12_crypto_info_aes_gcm_128() {
         )
     );
 }
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct tls12_crypto_info_aes_gcm_256 {
+    pub info: tls_crypto_info,
+    pub iv: [libc::c_uchar; 8usize],
+    pub key: [libc::c_uchar; 32usize],
+    pub salt: [libc::c_uchar; 4usize],
+    pub rec_seq: [libc::c_uchar; 8usize],
+}
+#[test]
+fn bindgen_test_layout_tls12_crypto_info_aes_gcm_256() {
+    assert_eq!(
+        ::core::mem::size_of::<tls12_crypto_info_aes_gcm_256>(),
+        56usize,
+        concat!("Size of: ", stringify!(tls12_crypto_info_aes_gcm_256))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<tls12_crypto_info_aes_gcm_256>(),
+        2usize,
+        concat!("Alignment of ", stringify!(tls12_crypto_info_aes_gcm_256))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_aes_gcm_256>())).info as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_aes_gcm_256),
+            "::",
+            stringify!(info)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_aes_gcm_256>())).iv as *const _ as usize
+        },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_aes_gcm_256),
+            "::",
+            stringify!(iv)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_aes_gcm_256>())).key as *const _ as usize
+        },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_aes_gcm_256),
+            "::",
+            stringify!(key)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_aes_gcm_256>())).salt as *const _ as usize
+        },
+        44usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_aes_gcm_256),
+            "::",
+            stringify!(salt)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_aes_gcm_256>())).rec_seq as *const _ as usize
+        },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_aes_gcm_256),
+            "::",
+            stringify!(rec_seq)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct tls12_crypto_info_chacha20_poly1305 {
+    pub info: tls_crypto_info,
+    pub iv: [libc::c_uchar; 12usize],
+    pub key: [libc::c_uchar; 32usize],
+    pub salt: [libc::c_uchar; 0usize],
+    pub rec_seq: [libc::c_uchar; 8usize],
+}
+#[test]
+fn bindgen_test_layout_tls12_crypto_info_chacha20_poly1305() {
+    assert_eq!(
+        ::core::mem::size_of::<tls12_crypto_info_chacha20_poly1305>(),
+        56usize,
+        concat!("Size of: ", stringify!(tls12_crypto_info_chacha20_poly1305))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<tls12_crypto_info_chacha20_poly1305>(),
+        2usize,
+        concat!("Alignment of ", stringify!(tls12_crypto_info_chacha20_poly1305))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_chacha20_poly1305>())).info as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_chacha20_poly1305),
+            "::",
+            stringify!(info)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_chacha20_poly1305>())).iv as *const _ as usize
+        },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_chacha20_poly1305),
+            "::",
+            stringify!(iv)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_chacha20_poly1305>())).key as *const _ as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_chacha20_poly1305),
+            "::",
+            stringify!(key)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_chacha20_poly1305>())).salt as *const _ as usize
+        },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_chacha20_poly1305),
+            "::",
+            stringify!(salt)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<tls12_crypto_info_chacha20_poly1305>())).rec_seq as *const _ as usize
+        },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(tls12_crypto_info_chacha20_poly1305),
+            "::",
+            stringify!(rec_seq)
+        )
+    );
+}
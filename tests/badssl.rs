@@ -1,14 +1,14 @@
 mod common;
 
-use std::net::ToSocketAddrs;
-use webpki::DNSNameRef;
+use std::net::{ self, ToSocketAddrs };
 use tokio::prelude::*;
 use tokio::io as aio;
-use tokio::net::TcpStream;
 use tokio::runtime::current_thread;
-use tokio_rustls::TlsConnector;
+use rustls::ClientConnection;
+use rustls::pki_types::ServerName;
+use ktls::rustls::RecordReader;
 use tokio_rusktls::KtlsStream;
-use self::common::get_client_config;
+use self::common::{ get_client_config, into_tokio };
 
 
 #[test]
@@ -16,13 +16,11 @@ fn test_tls12() {
     // https://tls-v1-2.badssl.com:1012/
 
     let hostname = "tls-v1-2.badssl.com";
-    let dnsname = DNSNameRef::try_from_ascii_str(hostname).unwrap();
+    let dnsname = ServerName::try_from(hostname).unwrap();
     let addr = (hostname, 1012)
         .to_socket_addrs().unwrap()
         .next().unwrap();
 
-    let connector = TlsConnector::from(get_client_config());
-
     let text = format!("\
         GET / HTTP/1.0\r\n\
         Host: {}\r\n\
@@ -30,13 +28,12 @@ fn test_tls12() {
         \r\n\
     ", hostname);
 
-    let done = TcpStream::connect(&addr)
-        .and_then(move |sock| connector.connect(dnsname, sock))
-        .and_then(|stream| {
-            let (io, session) = stream.into_inner();
-            KtlsStream::new(io, &session)
-                .map_err(|err| err.error)
-        })
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let mut sess = ClientConnection::new(get_client_config(), dnsname).unwrap().into();
+    RecordReader::new().complete_handshake(&mut sess, &mut sock).unwrap();
+
+    let done = future::result(KtlsStream::new(into_tokio(sock), sess))
+        .map_err(|err| err.error)
         .and_then(|stream| aio::write_all(stream, text.as_bytes()))
         .and_then(|(stream, _)| aio::read_to_end(stream, Vec::new()))
         .map(|(_, buf)| buf);
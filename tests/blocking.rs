@@ -0,0 +1,40 @@
+mod common;
+
+use std::thread;
+use std::io::{ Read, Write };
+use std::net::{ TcpListener, TcpStream, SocketAddr };
+use rustls::pki_types::ServerName;
+use ktls::State;
+use self::common::{ get_server_config, get_client_config };
+
+
+#[test]
+fn test_blocking_connect_accept() {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut stream = ktls::rustls::accept(sock, get_server_config()).unwrap();
+        assert_eq!(stream.server_name(), Some("localhost"));
+        assert_eq!(stream.state(), State::Open);
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+    });
+
+    let sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut stream = ktls::rustls::connect(sock, get_client_config(), dnsname).unwrap();
+    assert!(stream.peer_certificates().is_some());
+    assert_eq!(stream.protocol_version(), stream.handshake().protocol_version());
+    assert_eq!(stream.alpn_protocol(), None);
+
+    let mut buf = [0; 5];
+    stream.write_all(b"hello").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    server.join().unwrap();
+}
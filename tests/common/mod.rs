@@ -1,12 +1,20 @@
 #![allow(dead_code)]
 
+use std::{ net, thread };
 use std::sync::Arc;
-use std::io::{ self, BufReader, Cursor, Read, Write };
+use std::io::{ self, Read, Write };
 use std::os::unix::io::{ AsRawFd, RawFd };
 use tokio::prelude::*;
 use tokio::io as aio;
-use rustls::internal::pemfile::{ certs, rsa_private_keys };
-use rustls::{ ALL_CIPHERSUITES, ClientConfig, ServerConfig, NoClientAuth };
+use tokio::reactor::Handle;
+use rustls::{
+    ClientConfig, ServerConfig, ClientConnection, ServerConnection, Connection,
+    RootCertStore, SupportedCipherSuite, SupportedProtocolVersion
+};
+use rustls::crypto::{ CryptoProvider, ring };
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer, ServerName };
+use rustls::pki_types::pem::PemObject;
+use ktls::rustls::RecordReader;
 
 const CERT: &str = include_str!("end.cert");
 const CHAIN: &str = include_str!("end.chain");
@@ -14,27 +22,123 @@ const RSA: &str = include_str!("end.rsa");
 
 
 pub fn get_server_config() -> Arc<ServerConfig> {
-    let cert = certs(&mut BufReader::new(Cursor::new(CERT))).unwrap();
-    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+    server_config(ring::default_provider(), rustls::DEFAULT_VERSIONS)
+}
+
+pub fn get_client_config() -> Arc<ClientConfig> {
+    client_config(ring::default_provider())
+}
+
+/// The certificate chain the server sends, leaf first.
+pub fn get_certs() -> Vec<CertificateDer<'static>> {
+    CertificateDer::pem_slice_iter(CERT.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+}
+
+/// A server config that only offers `suite`.
+pub fn get_server_config_with(suite: SupportedCipherSuite) -> Arc<ServerConfig> {
+    let provider = CryptoProvider { cipher_suites: vec![suite], ..ring::default_provider() };
+    server_config(provider, &[suite.version()])
+}
+
+fn server_config(provider: CryptoProvider, versions: &[&'static SupportedProtocolVersion])
+    -> Arc<ServerConfig>
+{
+    let cert = get_certs();
+    let key = PrivateKeyDer::from_pem_slice(RSA.as_bytes()).unwrap();
 
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    config.set_single_cert(cert, keys.pop().unwrap())
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(cert, key)
         .expect("invalid key or certificate");
+    config.enable_secret_extraction = true;
     Arc::new(config)
 }
 
-pub fn get_client_config() -> Arc<ClientConfig> {
-    let mut chain = BufReader::new(Cursor::new(CHAIN));
-
-    let mut config = ClientConfig::new();
-    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-    config.root_store.add_pem_file(&mut chain).unwrap();
-    config.ciphersuites.clear();
-    config.ciphersuites.push(ALL_CIPHERSUITES[6]);
-    config.ciphersuites.push(ALL_CIPHERSUITES[8]);
+fn client_config(provider: CryptoProvider) -> Arc<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+    for cert in CertificateDer::pem_slice_iter(CHAIN.as_bytes()) {
+        root_store.add(cert.unwrap()).unwrap();
+    }
+
+    let mut config = ClientConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.enable_secret_extraction = true;
     Arc::new(config)
 }
 
+/// Run a server handshake to completion on a blocking socket.
+pub fn accept_with<T>(config: Arc<ServerConfig>, sock: &mut T) -> ServerConnection
+where T: Read + Write
+{
+    let mut sess = ServerConnection::new(config).unwrap().into();
+    RecordReader::new().complete_handshake(&mut sess, sock).unwrap();
+
+    match sess {
+        Connection::Server(sess) => sess,
+        Connection::Client(_) => unreachable!()
+    }
+}
+
+pub fn accept<T: Read + Write>(sock: &mut T) -> ServerConnection {
+    accept_with(get_server_config(), sock)
+}
+
+/// Run a client handshake with `localhost` to completion on a blocking socket.
+pub fn connect_with<T>(config: Arc<ClientConfig>, sock: &mut T) -> ClientConnection
+where T: Read + Write
+{
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut sess = ClientConnection::new(config, dnsname).unwrap().into();
+    RecordReader::new().complete_handshake(&mut sess, sock).unwrap();
+
+    match sess {
+        Connection::Client(sess) => sess,
+        Connection::Server(_) => unreachable!()
+    }
+}
+
+pub fn connect<T: Read + Write>(sock: &mut T) -> ClientConnection {
+    connect_with(get_client_config(), sock)
+}
+
+/// Accept one connection on a new thread, run the server handshake with `config`,
+/// and hand the socket and session to `f`.
+pub fn run_server_with<F>(config: Arc<ServerConfig>, f: F) -> net::SocketAddr
+where F: FnOnce(net::TcpStream, ServerConnection) + Send + 'static
+{
+    let addr = net::SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = net::TcpListener::bind(&addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let sess = accept_with(config, &mut sock);
+        f(sock, sess);
+    });
+
+    addr
+}
+
+pub fn run_server<F>(f: F) -> net::SocketAddr
+where F: FnOnce(net::TcpStream, ServerConnection) + Send + 'static
+{
+    run_server_with(get_server_config(), f)
+}
+
+/// Hand a socket that finished its handshake over to tokio.
+pub fn into_tokio(sock: net::TcpStream) -> tokio::net::TcpStream {
+    tokio::net::TcpStream::from_std(sock, &Handle::default()).unwrap()
+}
+
 pub struct ReadHalf<T>(RawFd, aio::ReadHalf<T>);
 pub struct WriteHalf<T>(RawFd, aio::WriteHalf<T>);
 
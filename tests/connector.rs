@@ -0,0 +1,81 @@
+mod common;
+
+use std::net::SocketAddr;
+use tokio::prelude::*;
+use tokio::io as aio;
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::runtime::current_thread;
+use rustls::pki_types::ServerName;
+use tokio_rusktls::{ KtlsConnector, KtlsAcceptor, MaybeKtls };
+use self::common::{ get_server_config, get_client_config };
+
+
+#[test]
+fn test_connect_accept() {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let acceptor = KtlsAcceptor::from(get_server_config());
+    let connector = KtlsConnector::from(get_client_config());
+
+    let server = listener.incoming()
+        .take(1)
+        .for_each(move |sock| acceptor.accept(sock)
+            .and_then(|stream| {
+                assert!(stream.is_ktls());
+                aio::read_exact(stream, [0; 5])
+            })
+            .and_then(|(stream, buf)| aio::write_all(stream, buf))
+            .and_then(|(stream, _)| aio::shutdown(stream))
+            .map(drop));
+
+    let client = TcpStream::connect(&addr)
+        .and_then(move |sock| {
+            let dnsname = ServerName::try_from("localhost").unwrap();
+            connector.connect(dnsname, sock)
+        })
+        .and_then(|stream| {
+            assert!(stream.is_ktls());
+            aio::write_all(stream, b"hello")
+        })
+        .and_then(|(stream, _)| aio::read_to_end(stream, Vec::new()))
+        .map(|(_, buf)| buf);
+
+    let (_, output) = current_thread::block_on_all(server.join(client)).unwrap();
+    assert_eq!(output, b"hello");
+}
+
+#[test]
+fn test_connect_accept_exports() {
+    const EXPORTS: &[(&[u8], Option<&[u8]>, usize)] = &[(b"EXPERIMENTAL-ktls", None, 32)];
+
+    fn exported<IO>(stream: &MaybeKtls<IO>) -> Vec<u8> {
+        match stream {
+            MaybeKtls::Ktls(stream) => stream.export_keying_material(b"EXPERIMENTAL-ktls", None, 32)
+                .unwrap(),
+            MaybeKtls::Rustls(_) => panic!("kTLS was not installed")
+        }
+    }
+
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let acceptor = KtlsAcceptor::from(get_server_config()).exports(EXPORTS);
+    let connector = KtlsConnector::from(get_client_config()).exports(EXPORTS);
+
+    let server = listener.incoming()
+        .into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(sock, _)| acceptor.accept(sock.unwrap()))
+        .map(|stream| exported(&stream));
+
+    let client = TcpStream::connect(&addr)
+        .and_then(move |sock| {
+            let dnsname = ServerName::try_from("localhost").unwrap();
+            connector.connect(dnsname, sock)
+        })
+        .map(|stream| exported(&stream));
+
+    let (server, client) = current_thread::block_on_all(server.join(client)).unwrap();
+    assert_eq!(server, client);
+}
@@ -1,97 +1,314 @@
 mod common;
 
-use std::thread;
-use std::sync::mpsc::{ channel, Receiver };
-use std::net::{ TcpListener, SocketAddr };
-use tokio::prelude::*;
-use tokio::net::TcpStream;
-use tokio::runtime::current_thread;
-use webpki::DNSNameRef;
-use rustls::{ Session, ServerSession, TLSError };
-use rustls::internal::msgs::{
-    alert::AlertMessagePayload,
-    message::{
-        Message,
-        MessagePayload
-    },
-    enums::{
-        ContentType,
-        ProtocolVersion,
-        AlertLevel,
-        AlertDescription
-    }
-};
-use tokio_rustls::TlsConnector;
+use std::{ net, thread };
+use std::io::{ self, Read, Write };
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use rustls::{ AlertDescription, HandshakeKind };
+use rustls::pki_types::ServerName;
+use ktls::codec::{ self, Level, ContentType };
+use ktls::{ KtlsErrorKind, State, SessionTicket };
 use tokio_rusktls::KtlsStream;
-use self::common::{ get_server_config, get_client_config };
+use self::common::{
+    get_server_config, get_client_config, connect, connect_with, into_tokio, run_server, run_server_with
+};
 
 
 #[test]
 fn test_alert() {
-    fn run_server() -> (SocketAddr, Receiver<TLSError>) {
-        let config = get_server_config();
+    let (send, recv) = channel();
+
+    let addr = run_server(move |mut sock, mut sess| {
+        sess.read_tls(&mut sock).unwrap();
+        let err = sess.process_new_packets().unwrap_err();
+
+        send.send(err).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(into_tokio(sock), sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let err = stream.send_record(ContentType::ApplicationData, b"hello").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    stream.send_alert(Level::Fatal, codec::AlertDescription::InternalError).unwrap();
+    assert_eq!(
+        stream.state(),
+        State::Failed(KtlsErrorKind::AlertSent(codec::AlertDescription::InternalError))
+    );
+
+    let err = recv.recv().unwrap();
+    assert_eq!(err, rustls::Error::AlertReceived(AlertDescription::InternalError));
+}
+
+#[test]
+fn test_fatal_alert() {
+    let addr = run_server(|sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        stream.send_alert(Level::Warning, codec::AlertDescription::UserCanceled).unwrap();
+        stream.write_all(b"hi").unwrap();
+        stream.send_alert(Level::Fatal, codec::AlertDescription::HandshakeFailure).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+    assert_eq!(
+        stream.last_alert(),
+        Some((Level::Warning, codec::AlertDescription::UserCanceled))
+    );
+
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(
+        KtlsErrorKind::of(&err),
+        Some(KtlsErrorKind::AlertReceived(codec::AlertDescription::HandshakeFailure))
+    );
+    assert_eq!(
+        stream.last_alert(),
+        Some((Level::Fatal, codec::AlertDescription::HandshakeFailure))
+    );
+    assert_eq!(
+        stream.state(),
+        State::Failed(KtlsErrorKind::AlertReceived(codec::AlertDescription::HandshakeFailure))
+    );
+}
+
+#[test]
+fn test_bad_record_mac() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |mut sock, mut sess| {
+        // application data record that can not authenticate
+        let mut record = vec![0x17, 0x03, 0x03, 0x00, 0x20];
+        record.extend_from_slice(&[0x42; 0x20]);
+        sock.write_all(&record).unwrap();
+
+        sess.read_tls(&mut sock).unwrap();
+        let err = sess.process_new_packets().unwrap_err();
+
+        send.send(err).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let mut buf = [0; 32];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(KtlsErrorKind::of(&err), Some(KtlsErrorKind::BadRecordMac));
+
+    let err = recv.recv().unwrap();
+    assert_eq!(err, rustls::Error::AlertReceived(AlertDescription::BadRecordMac));
+
+    assert_eq!(stream.state(), State::Failed(KtlsErrorKind::BadRecordMac));
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(KtlsErrorKind::of(&err), Some(KtlsErrorKind::BadRecordMac));
+    let err = stream.write(b"hello").unwrap_err();
+    assert_eq!(KtlsErrorKind::of(&err), Some(KtlsErrorKind::BadRecordMac));
+}
+
+#[test]
+fn test_refuse_renegotiation() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        // hello_request, empty body
+        stream.send_record(ContentType::Handshake, &[0, 0, 0, 0]).unwrap();
+        stream.write_all(b"hi").unwrap();
 
-        let (send, recv) = channel();
-        let (send2, recv2) = channel();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ok");
 
-        thread::spawn(move || {
-            let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-            let listener = TcpListener::bind(&addr).unwrap();
+        send.send(stream.last_alert()).unwrap();
+    });
 
-            send.send(listener.local_addr().unwrap()).unwrap();
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
 
-            let (mut sock, _) = listener.accept().unwrap();
-            let config = config.clone();
-            let mut sess = ServerSession::new(&config);
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
 
-            sess.complete_io(&mut sock).unwrap();
-            if sess.wants_write() {
-                sess.complete_io(&mut sock).unwrap();
-            }
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+    assert_eq!(stream.state(), State::Open);
+    stream.write_all(b"ok").unwrap();
 
-            sess.read_tls(&mut sock).unwrap();
-            let err = sess.process_new_packets().unwrap_err();
+    let alert = recv.recv().unwrap();
+    assert_eq!(alert, Some((Level::Warning, codec::AlertDescription::NoRenegotiation)));
+}
+
+#[test]
+fn test_session_ticket() {
+    // no tickets from rustls, so the one sent here is the only one
+    let mut config = (*get_server_config()).clone();
+    config.send_tls13_tickets = 0;
+
+    let addr = run_server_with(Arc::new(config), |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let ticket = [
+            4, 0, 0, 17,        // new_session_ticket
+            0, 0, 0x1c, 0x20,   // lifetime
+            1, 2, 3, 4,         // age_add
+            1, 0,               // nonce
+            0, 3, 7, 8, 9,      // ticket
+            0, 0                // extensions
+        ];
+        // split across records, as a peer may
+        stream.send_record(ContentType::Handshake, &ticket[..6]).unwrap();
+        stream.send_record(ContentType::Handshake, &ticket[6..]).unwrap();
+        stream.write_all(b"hi").unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let (send, recv) = channel();
+    stream.set_ticket_handler(move |ticket| send.send(ticket).unwrap());
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+
+    let ticket = recv.try_recv().unwrap();
+    assert_eq!(ticket, SessionTicket {
+        lifetime: 7200,
+        age_add: 0x01020304,
+        nonce: vec![0],
+        ticket: vec![7, 8, 9],
+        extensions: Vec::new()
+    });
+    assert!(recv.try_recv().is_err());
+}
+
+#[test]
+fn test_session_ticket_resumption() {
+    // one server config for both connections, so the second can resume
+    let config = get_server_config();
+    let client_config = get_client_config();
+
+    let addr = run_server_with(config.clone(), |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        stream.write_all(b"hi").unwrap();
+    });
+
+    let sock = net::TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut stream = ktls::rustls::connect_kernel(sock, client_config.clone(), dnsname).unwrap();
+
+    // the tickets rustls sent after the handshake come in over kTLS, ahead of the data
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+
+    let (send, recv) = channel();
+    let addr = run_server_with(config, move |_sock, sess| {
+        send.send(sess.handshake_kind()).unwrap();
+    });
 
-            send2.send(err).unwrap();
-        });
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect_with(client_config, &mut sock);
+    assert_eq!(sess.handshake_kind(), Some(HandshakeKind::Resumed));
+    assert_eq!(recv.recv().unwrap(), Some(HandshakeKind::Resumed));
+}
+
+#[test]
+fn test_key_update() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        // key_update, update_not_requested
+        stream.send_record(ContentType::Handshake, &[24, 0, 0, 1, 0]).unwrap();
+
+        // the client gives up without an alert
+        let mut buf = [0; 1];
+        send.send(stream.read(&mut buf)).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let mut buf = [0; 1];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(KtlsErrorKind::of(&err), Some(KtlsErrorKind::KeyUpdate));
+    assert_eq!(stream.state(), State::Failed(KtlsErrorKind::KeyUpdate));
+    drop(stream);
 
-        let addr = recv.recv().unwrap();
-        (addr, recv2)
+    match recv.recv().unwrap() {
+        Ok(0) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset => (),
+        result => panic!("{:?}", result)
     }
+}
+
+#[test]
+fn test_kernel_key_update() {
+    let (send, recv) = channel();
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::spawn(move || {
+        let (sock, _) = listener.accept().unwrap();
+        let mut stream = ktls::rustls::accept_kernel(sock, get_server_config()).unwrap();
+
+        // the client is asked to move to a new key as well
+        stream.send_key_update(true).unwrap();
+        stream.write_all(b"hi").unwrap();
+
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        send.send(buf).unwrap();
+    });
+
+    let sock = net::TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut stream = ktls::rustls::connect_kernel(sock, get_client_config(), dnsname).unwrap();
+
+    let mut buf = [0; 2];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hi");
+    stream.write_all(b"ok").unwrap();
 
-    let (addr, recv2) = run_server();
-
-    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
-    let connector = TlsConnector::from(get_client_config());
-
-    let record = Message {
-        typ: ContentType::Alert,
-        version: ProtocolVersion::TLSv1_2,
-        payload: MessagePayload::Alert(AlertMessagePayload {
-            level: AlertLevel::Fatal,
-            description: AlertDescription::InternalError
-        })
-    };
-
-    let done = TcpStream::connect(&addr)
-        .and_then(move |sock| connector.connect(dnsname, sock))
-        .and_then(|stream| {
-            let (io, session) = stream.into_inner();
-            KtlsStream::new(io, &session)
-                .map_err(|err| err.error)
-        })
-        .and_then(|stream| unsafe {
-            let mut io = stream.into_inner();
-
-            ktls::sys::send_ctrl_message(
-                io.get_mut(),
-                ContentType::Alert.get_u8(),
-                &record.take_payload()
-            )
-        });
-
-    current_thread::block_on_all(done).unwrap();
-
-    let err = recv2.recv().unwrap();
-    assert_eq!(err, TLSError::AlertReceived(AlertDescription::InternalError));
+    assert_eq!(&recv.recv().unwrap(), b"ok");
+    assert_eq!(stream.state(), State::Open);
 }
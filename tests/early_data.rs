@@ -0,0 +1,64 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use rustls::{ ClientConnection, StreamOwned };
+use rustls::pki_types::ServerName;
+use tokio_rusktls::KtlsStream;
+use self::common::{ get_server_config, get_client_config, connect_with, run_server_with };
+
+
+#[test]
+fn test_early_data() {
+    let mut server_config = (*get_server_config()).clone();
+    server_config.max_early_data_size = 1024;
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = (*get_client_config()).clone();
+    client_config.enable_early_data = true;
+    let client_config = Arc::new(client_config);
+
+    // a first connection, for the client to get a ticket
+    let addr = run_server_with(server_config.clone(), |sock, sess| {
+        StreamOwned::new(sess, sock).write_all(b"ticket").unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect_with(client_config.clone(), &mut sock);
+    let mut buf = [0; 6];
+    StreamOwned::new(sess, sock).read_exact(&mut buf).unwrap();
+
+    // resumed on a server of its own, with the same config and so the same tickets
+    let (send, recv) = channel();
+
+    let addr = run_server_with(server_config, move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        assert_eq!(stream.early_data(), Some(&b"hello"[..]));
+
+        // reads only see what came after the handshake
+        let mut buf = [0; 6];
+        stream.read_exact(&mut buf).unwrap();
+        send.send(buf).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut sess = ClientConnection::new(client_config, dnsname).unwrap();
+
+    sess.early_data().unwrap().write_all(b"hello").unwrap();
+    while sess.is_handshaking() || sess.wants_write() {
+        sess.complete_io(&mut sock).unwrap();
+    }
+    assert!(sess.is_early_data_accepted());
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+    stream.write_all(b" world").unwrap();
+
+    assert_eq!(&recv.recv().unwrap(), b" world");
+}
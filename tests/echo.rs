@@ -1,38 +1,33 @@
 mod common;
 
-use std::{ thread, io };
+use std::{ thread, io, net };
+use std::io::{ Read, Write };
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use tokio::prelude::*;
-use tokio::io as aio;
 use tokio::runtime::current_thread;
-use tokio::net::{ TcpListener, TcpStream };
-use webpki::DNSNameRef;
-use tokio_rustls::{ TlsConnector, TlsAcceptor };
+use rustls::StreamOwned;
 use tokio_rusktls::KtlsStream;
 use tokio_linux_zio as zio;
-use self::common::{ get_client_config, get_server_config, split };
+use self::common::{ accept, connect, into_tokio, split };
 
 
 #[test]
 fn test_splice() {
     fn run_server() -> SocketAddr {
-        let acceptor = TlsAcceptor::from(get_server_config());
         let (send, recv) = channel();
 
         thread::spawn(move || {
             let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-            let listener = TcpListener::bind(&addr).unwrap();
+            let listener = net::TcpListener::bind(&addr).unwrap();
 
             send.send(listener.local_addr().unwrap()).unwrap();
 
-            let done = listener.incoming()
-                .and_then(|sock| acceptor.accept(sock))
-                .and_then(|stream| {
-                    let (io, session) = stream.into_inner();
-                    KtlsStream::new(io, &session)
-                        .map_err(|err| err.error)
-                })
+            let (mut sock, _) = listener.accept().unwrap();
+            let sess = accept(&mut sock);
+
+            let done = future::result(KtlsStream::new(into_tokio(sock), sess))
+                .map_err(|err| err.error)
                 .and_then(|kstream| zio::pipe()
                     .map(move |pipes| (pipes, kstream)))
                 .and_then(|((pr, pw), kstream)| {
@@ -50,8 +45,7 @@ fn test_splice() {
                         } else {
                             Err(err)
                         })
-                })
-                .for_each(|_| Ok(()));
+                });
 
             current_thread::block_on_all(done).unwrap();
         });
@@ -61,17 +55,14 @@ fn test_splice() {
 
     let addr = run_server();
 
-    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
-    let connector = TlsConnector::from(get_client_config());
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+    let mut stream = StreamOwned::new(sess, sock);
 
     let input = b"hello world!";
+    let mut output = vec![0; input.len()];
 
-    let done = TcpStream::connect(&addr)
-        .and_then(move |sock| connector.connect(dnsname, sock))
-        .and_then(|stream| aio::write_all(stream, input))
-        .and_then(|(stream, input)| aio::read_exact(stream, vec![0; input.len()]))
-        .map(|(_, buf)| buf);
-
-    let output = current_thread::block_on_all(done).unwrap();
+    stream.write_all(input).unwrap();
+    stream.read_exact(&mut output).unwrap();
     assert_eq!(output, input);
 }
@@ -0,0 +1,252 @@
+mod common;
+
+use std::io;
+use std::io::{ Read, Write };
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::net::TcpStream;
+use rustls::{ ProtocolVersion, ClientConnection, Connection, StreamOwned };
+use rustls::pki_types::ServerName;
+use ktls::KtlsErrorKind;
+use ktls::rustls::RecordReader;
+use tokio_rusktls::{ KtlsStream, ChannelBinding };
+use self::common::{
+    get_server_config, get_client_config, get_certs, connect_with, run_server, run_server_with
+};
+
+
+#[test]
+fn test_handshake_info() {
+    let (send, recv) = channel();
+    let mut config = (*get_server_config()).clone();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let addr = run_server_with(Arc::new(config), move |sock, sess| {
+        let suite = sess.negotiated_cipher_suite().unwrap();
+
+        let stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        assert_eq!(stream.protocol_version(), ProtocolVersion::TLSv1_3);
+        assert!(stream.cipher_suite() == suite);
+        assert_eq!(stream.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(stream.server_name(), Some("localhost"));
+        assert!(stream.peer_certificates().is_none());
+        send.send(()).unwrap();
+    });
+
+    let mut config = (*get_client_config()).clone();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect_with(Arc::new(config), &mut sock);
+    let certs = sess.peer_certificates().unwrap().to_vec();
+
+    let stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    assert_eq!(stream.alpn_protocol(), Some(&b"http/1.1"[..]));
+    assert_eq!(stream.peer_certificates(), Some(&certs[..]));
+    assert_eq!(stream.server_name(), None);
+
+    recv.recv().unwrap();
+}
+
+#[test]
+fn test_export_keying_material() {
+    const EXPORTS: &[(&[u8], Option<&[u8]>, usize)] = &[
+        (b"EXPERIMENTAL-ktls", None, 32),
+        (b"EXPERIMENTAL-ktls", Some(b"context"), 64)
+    ];
+
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let stream = KtlsStream::with_exports(sock, sess, EXPORTS)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        send.send(stream.export_keying_material(b"EXPERIMENTAL-ktls", Some(b"context"), 64).unwrap())
+            .unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect_with(get_client_config(), &mut sock);
+    let expected = sess.export_keying_material(vec![0; 32], b"EXPERIMENTAL-ktls", None).unwrap();
+
+    let stream = KtlsStream::with_exports(sock, sess, EXPORTS)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    assert_eq!(stream.export_keying_material(b"EXPERIMENTAL-ktls", None, 32).unwrap(), expected);
+    assert_eq!(
+        stream.export_keying_material(b"EXPERIMENTAL-ktls", Some(b"context"), 64).unwrap(),
+        recv.recv().unwrap()
+    );
+
+    let err = stream.export_keying_material(b"EXPERIMENTAL-other", None, 32).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_export_keying_material_on_demand() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |_sock, sess| {
+        let exported = sess.export_keying_material(vec![0; 48], b"EXPERIMENTAL-ktls", Some(b"context"))
+            .unwrap();
+        send.send(exported).unwrap();
+    });
+
+    // TLS 1.3, so nothing has to be asked for at install
+    let sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let stream = ktls::rustls::connect(sock, get_client_config(), dnsname).unwrap();
+    assert_eq!(stream.protocol_version(), ProtocolVersion::TLSv1_3);
+
+    assert_eq!(
+        stream.export_keying_material(b"EXPERIMENTAL-ktls", Some(b"context"), 48).unwrap(),
+        recv.recv().unwrap()
+    );
+}
+
+#[test]
+fn test_channel_binding() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let err = stream.channel_binding(ChannelBinding::TlsServerEndPoint).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        stream.set_server_certificate(get_certs().remove(0));
+
+        send.send((
+            stream.channel_binding(ChannelBinding::TlsServerEndPoint).unwrap(),
+            stream.channel_binding(ChannelBinding::TlsExporter).unwrap()
+        )).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect_with(get_client_config(), &mut sock);
+
+    let stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let end_point = stream.channel_binding(ChannelBinding::TlsServerEndPoint).unwrap();
+    let exporter = stream.channel_binding(ChannelBinding::TlsExporter).unwrap();
+    assert_eq!(end_point.len(), 32);
+    assert_eq!(exporter.len(), 32);
+    assert_eq!(recv.recv().unwrap(), (end_point, exporter));
+}
+
+#[test]
+fn test_handshake_incomplete() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        send.send(buf).unwrap();
+    });
+
+    let sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let sess = ClientConnection::new(get_client_config(), dnsname).unwrap();
+
+    // too early, but the session comes back to finish the handshake with
+    let err = KtlsStream::new(sock, sess).unwrap_err();
+    assert_eq!(err.kind(), Some(KtlsErrorKind::HandshakeIncomplete));
+
+    let (mut sock, sess) = err.inner;
+    let mut sess = sess.unwrap();
+    RecordReader::new().complete_handshake(&mut sess, &mut sock).unwrap();
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+    stream.write_all(b"hi").unwrap();
+
+    assert_eq!(&recv.recv().unwrap(), b"hi");
+}
+
+#[test]
+fn test_partial_record() {
+    let (send, recv) = channel();
+    let (send_reply, reply) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        stream.write_all(b"hello").unwrap();
+        send.send(()).unwrap();
+
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        send_reply.send(buf).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let mut sess = connect_with(get_client_config(), &mut sock);
+    recv.recv().unwrap();
+
+    // hand rustls the start of a record, as a plain read_tls may
+    let mut buf = [0; 8];
+    sock.read_exact(&mut buf).unwrap();
+    sess.read_tls(&mut &buf[..]).unwrap();
+
+    let err = KtlsStream::new(sock, sess).unwrap_err();
+    assert_eq!(err.kind(), Some(KtlsErrorKind::PartialRecord));
+
+    // the session comes back intact, to go on in userspace
+    let (sock, sess) = match err.inner {
+        (sock, Some(Connection::Client(sess))) => (sock, sess),
+        _ => panic!("session was not handed back")
+    };
+    let mut stream = StreamOwned::new(sess, sock);
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    stream.write_all(b"ok").unwrap();
+
+    assert_eq!(&reply.recv().unwrap(), b"ok");
+}
+
+#[test]
+fn test_pipelined_record() {
+    let (send, recv) = channel();
+
+    // accept reads the handshake record by record, and leaves what follows to the kernel
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        send.send(buf).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut sess = ClientConnection::new(get_client_config(), dnsname).unwrap();
+
+    // queued during the handshake, sent right behind the client Finished
+    sess.writer().write_all(b"hello").unwrap();
+    while sess.is_handshaking() || sess.wants_write() {
+        sess.complete_io(&mut sock).unwrap();
+    }
+
+    assert_eq!(&recv.recv().unwrap(), b"hello");
+}
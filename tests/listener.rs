@@ -0,0 +1,93 @@
+mod common;
+
+use std::{ io, thread };
+use std::io::{ Read, Write };
+use std::net::{ TcpListener, TcpStream, SocketAddr };
+use std::time::Duration;
+use rustls::pki_types::ServerName;
+use ktls::KtlsListener;
+use self::common::{ get_server_config, get_client_config };
+
+
+#[test]
+fn test_listener_incoming() {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut listener = KtlsListener::new(listener, get_server_config());
+    listener.set_workers(2);
+    listener.set_handshake_timeout(Some(Duration::from_millis(500)));
+
+    // connects, but never starts the handshake
+    let _slow = TcpStream::connect(&addr).unwrap();
+
+    let client = thread::spawn(move || {
+        let sock = TcpStream::connect(&addr).unwrap();
+        let dnsname = ServerName::try_from("localhost").unwrap();
+        let mut stream = ktls::rustls::connect(sock, get_client_config(), dnsname).unwrap();
+        stream.write_all(b"hello").unwrap();
+    });
+
+    let mut incoming = listener.incoming();
+
+    let mut stream = incoming.next().unwrap().unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    let err = incoming.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    client.join().unwrap();
+}
+
+#[test]
+fn test_listener_exports() {
+    const EXPORTS: &[(&[u8], Option<&[u8]>, usize)] = &[(b"EXPERIMENTAL-ktls", None, 32)];
+
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut listener = KtlsListener::new(listener, get_server_config());
+    listener.set_exports(EXPORTS);
+
+    let client = thread::spawn(move || {
+        let sock = TcpStream::connect(&addr).unwrap();
+        let dnsname = ServerName::try_from("localhost").unwrap();
+        let stream = ktls::rustls::connect_with_exports(sock, get_client_config(), dnsname, EXPORTS)
+            .unwrap();
+        stream.handshake().export_keying_material(b"EXPERIMENTAL-ktls", None, 32).unwrap()
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let exported = stream.handshake().export_keying_material(b"EXPERIMENTAL-ktls", None, 32).unwrap();
+    assert_eq!(exported, client.join().unwrap());
+}
+
+#[test]
+fn test_listener_workers_stop() {
+    let listener = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut listener = KtlsListener::new(listener, get_server_config());
+    listener.set_workers(2);
+    drop(listener.incoming());
+
+    // give the workers time to see they should stop
+    thread::sleep(Duration::from_millis(500));
+
+    let client = thread::spawn(move || {
+        let sock = TcpStream::connect(&addr).unwrap();
+        let dnsname = ServerName::try_from("localhost").unwrap();
+        let mut stream = ktls::rustls::connect(sock, get_client_config(), dnsname).unwrap();
+        stream.write_all(b"hello").unwrap();
+    });
+
+    // the connection is left for this thread
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    client.join().unwrap();
+}
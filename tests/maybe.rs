@@ -0,0 +1,109 @@
+mod common;
+
+use std::thread;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::os::unix::net::UnixStream;
+use rustls::{ ClientConnection, StreamOwned };
+use rustls::pki_types::ServerName;
+use ktls::KtlsErrorKind;
+use ktls::rustls::RecordReader;
+use tokio_rusktls::MaybeKtls;
+use self::common::{ get_client_config, accept, connect, run_server };
+
+
+#[test]
+fn test_maybe_ktls() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = MaybeKtls::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        assert!(stream.is_ktls());
+        assert!(stream.fallback_reason().is_none());
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        send.send(()).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+    let mut stream = StreamOwned::new(sess, sock);
+
+    let mut buf = [0; 5];
+    stream.write_all(b"hello").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    recv.recv().unwrap();
+}
+
+#[test]
+fn test_maybe_rustls() {
+    // the tls ULP only attaches to TCP sockets
+    let (mut server_sock, mut client_sock) = UnixStream::pair().unwrap();
+
+    let server = thread::spawn(move || {
+        let sess = accept(&mut server_sock);
+
+        let mut stream = MaybeKtls::new(server_sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        assert!(!stream.is_ktls());
+        assert!(stream.fallback_reason().is_some());
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+
+        if let MaybeKtls::Rustls(ref mut stream) = stream {
+            stream.get_mut().1.send_close_notify();
+        }
+        stream.flush().unwrap();
+    });
+
+    let sess = connect(&mut client_sock);
+    let mut stream = StreamOwned::new(sess, client_sock);
+
+    let mut buf = Vec::new();
+    stream.write_all(b"hello").unwrap();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_maybe_handshake_incomplete() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |mut sock, _sess| {
+        // stay connected until the client is done
+        let _ = sock.read_to_end(&mut Vec::new());
+        send.send(()).unwrap();
+    });
+
+    let sock = TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let sess = ClientConnection::new(get_client_config(), dnsname).unwrap();
+
+    // not a reason to give up on kTLS, the session comes back to finish first
+    let err = MaybeKtls::new(sock, sess).unwrap_err();
+    assert_eq!(err.kind(), Some(KtlsErrorKind::HandshakeIncomplete));
+
+    let (mut sock, sess) = err.inner;
+    let mut sess = sess.unwrap();
+    RecordReader::new().complete_handshake(&mut sess, &mut sock).unwrap();
+
+    let stream = MaybeKtls::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+    assert!(stream.is_ktls());
+    drop(stream);
+
+    recv.recv().unwrap();
+}
@@ -0,0 +1,179 @@
+mod common;
+
+use std::{ io, net };
+use std::io::{ Read, Write };
+use std::sync::mpsc::channel;
+use tokio::prelude::*;
+use tokio::io as aio;
+use tokio::runtime::current_thread;
+use rustls::{ ClientConnection, StreamOwned };
+use rustls::pki_types::ServerName;
+use ktls::codec::ContentType;
+use tokio_rusktls::KtlsStream;
+use self::common::{ get_client_config, connect, into_tokio, run_server };
+
+
+#[test]
+fn test_write_record() {
+    let addr = run_server(|sock, sess| {
+        let done = future::result(KtlsStream::new(into_tokio(sock), sess))
+            .map_err(|err| err.error)
+            .and_then(|mut stream| {
+                stream.write_record(b"hello ")?;
+                stream.write_record(b"world")?;
+
+                let err = stream.write_record(&[0; 16 * 1024 + 1]).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+                Ok(stream)
+            })
+            .and_then(aio::shutdown);
+
+        current_thread::block_on_all(done).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let mut sess = connect(&mut sock);
+
+    // hand rustls one record at a time, so each read is one record's payload
+    let mut records = Vec::new();
+
+    loop {
+        let mut record = vec![0; 5];
+        sock.read_exact(&mut record).unwrap();
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(5 + len, 0);
+        sock.read_exact(&mut record[5..]).unwrap();
+
+        sess.read_tls(&mut &record[..]).unwrap();
+        let state = sess.process_new_packets().unwrap();
+
+        if state.peer_has_closed() {
+            break
+        }
+
+        let n = state.plaintext_bytes_to_read();
+        if n > 0 {
+            let mut buf = vec![0; n];
+            sess.reader().read_exact(&mut buf).unwrap();
+            records.push(buf);
+        }
+    }
+
+    assert_eq!(records, vec![b"hello ".to_vec(), b"world".to_vec()]);
+}
+
+#[test]
+fn test_read_record() {
+    let (send, recv) = channel();
+
+    let addr = run_server(move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let mut records = Vec::new();
+        let mut buf = [0; 1024];
+
+        loop {
+            let (typ, n) = stream.read_record(&mut buf).unwrap();
+            records.push((typ, buf[..n].to_vec()));
+
+            if n == 0 || typ == ContentType::Alert {
+                break
+            }
+        }
+
+        send.send(records).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = StreamOwned::new(sess, sock);
+    stream.write_all(b"hello").unwrap();
+    stream.conn.send_close_notify();
+    stream.flush().unwrap();
+
+    let records = recv.recv().unwrap();
+    assert_eq!(records, vec![
+        (ContentType::ApplicationData, b"hello".to_vec()),
+        // warning, close_notify
+        (ContentType::Alert, vec![1, 0])
+    ]);
+}
+
+#[test]
+fn test_buffered_plaintext() {
+    let (sent, recv_sent) = channel();
+    let (send_installed, installed) = channel();
+    let (send_output, output) = channel();
+
+    let addr = run_server(move |mut sock, mut sess| {
+        // pull the pipelined request into rustls before install
+        recv_sent.recv().unwrap();
+        sock.set_nonblocking(true).unwrap();
+        match sess.read_tls(&mut sock) {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            result => { result.unwrap(); }
+        }
+        sock.set_nonblocking(false).unwrap();
+
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+        send_installed.send(()).unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        send_output.send(buf).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let dnsname = ServerName::try_from("localhost").unwrap();
+    let mut sess = ClientConnection::new(get_client_config(), dnsname).unwrap();
+
+    // queued during the handshake, sent as soon as it completes
+    sess.writer().write_all(b"hello").unwrap();
+    while sess.is_handshaking() || sess.wants_write() {
+        sess.complete_io(&mut sock).unwrap();
+    }
+    sent.send(()).unwrap();
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    installed.recv().unwrap();
+    stream.write_all(b" world").unwrap();
+    stream.get_mut().shutdown(net::Shutdown::Write).unwrap();
+
+    assert_eq!(output.recv().unwrap(), b"hello world");
+}
+
+#[test]
+fn test_pending_write() {
+    let addr = run_server(|sock, mut sess| {
+        // encrypted by rustls, but not written yet
+        sess.writer().write_all(b"hello").unwrap();
+        assert!(sess.wants_write());
+
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        stream.write_all(b" world").unwrap();
+        stream.get_mut().shutdown(net::Shutdown::Write).unwrap();
+    });
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello world");
+}
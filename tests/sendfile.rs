@@ -1,68 +1,95 @@
 mod common;
 
-use std::{ thread, fs };
-use std::net::SocketAddr;
-use std::sync::mpsc::channel;
+use std::{ fs, net, io };
+use std::io::{ Read, Write };
+use std::ops::Bound;
 use tokio::prelude::*;
 use tokio::io as aio;
 use tokio::runtime::current_thread;
-use tokio::net::{ TcpListener, TcpStream };
-use webpki::DNSNameRef;
-use tokio_rustls::{ TlsConnector, TlsAcceptor };
+use rustls::StreamOwned;
+use ktls::SendFileProgress;
 use tokio_rusktls::KtlsStream;
 use tokio_linux_zio as zio;
-use self::common::{ get_client_config, get_server_config };
+use self::common::{ connect, into_tokio, run_server };
 
 
 #[test]
 fn test_sendfile() {
-    fn run_server() -> SocketAddr {
-        let acceptor = TlsAcceptor::from(get_server_config());
-        let (send, recv) = channel();
-
-        thread::spawn(move || {
-            let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-            let listener = TcpListener::bind(&addr).unwrap();
-
-            send.send(listener.local_addr().unwrap()).unwrap();
-
-            let done = listener.incoming()
-                .and_then(|sock| acceptor.accept(sock))
-                .and_then(|stream| {
-                    let (io, session) = stream.into_inner();
-                    KtlsStream::new(io, &session)
-                        .map_err(|err| err.error)
-                })
-                .and_then(|stream| aio::read_exact(stream, [0; 3]))
-                .and_then(|(stream, buf)| {
-                    assert_eq!(&buf, b"aaa");
-                    let fd = fs::File::open("Cargo.toml").unwrap();
-                    zio::sendfile(stream, fd, ..22)
-                })
-                .and_then(|(stream, ..)| aio::shutdown(stream))
-                .for_each(|_| Ok(()));
-
-            current_thread::block_on_all(done).unwrap();
-        });
-
-        recv.recv().unwrap()
-    }
-
-    let addr = run_server();
-
-    let dnsname = DNSNameRef::try_from_ascii_str("localhost").unwrap();
-    let connector = TlsConnector::from(get_client_config());
+    let addr = run_server(|sock, sess| {
+        let done = future::result(KtlsStream::new(into_tokio(sock), sess))
+            .map_err(|err| err.error)
+            .and_then(|stream| aio::read_exact(stream, [0; 3]))
+            .and_then(|(stream, buf)| {
+                assert_eq!(&buf, b"aaa");
+                let fd = fs::File::open("Cargo.toml").unwrap();
+                zio::sendfile(stream, fd, ..22)
+            })
+            .and_then(|(stream, ..)| aio::shutdown(stream));
+
+        current_thread::block_on_all(done).unwrap();
+    });
 
     let mut fd = fs::File::open("Cargo.toml").unwrap();
     let mut buf = [0; 22];
     fd.read_exact(&mut buf).unwrap();
 
-    let done = TcpStream::connect(&addr)
-        .and_then(move |sock| connector.connect(dnsname, sock))
-        .and_then(|stream| aio::write_all(stream, b"aaa"))
-        .and_then(|(stream, _)| aio::read_to_end(stream, Vec::new()))
-        .map(|(_, buf)| buf);
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+    let mut stream = StreamOwned::new(sess, sock);
+
+    let mut buf2 = Vec::new();
+    stream.write_all(b"aaa").unwrap();
+    stream.read_to_end(&mut buf2).unwrap();
+    assert_eq!(buf2, buf);
+}
+
+#[test]
+fn test_send_with_file() {
+    const HEADER: &[u8] = b"HTTP/1.0 200 OK\r\n\r\n";
+
+    let addr = run_server(|sock, sess| {
+        let done = future::result(KtlsStream::new(into_tokio(sock), sess))
+            .map_err(|err| err.error)
+            .and_then(|stream| aio::read_exact(stream, [0; 3]))
+            .and_then(|(stream, buf)| {
+                assert_eq!(&buf, b"aaa");
+                let fd = fs::File::open("Cargo.toml").unwrap();
+                stream.send_with_file(HEADER, fd, ..22)
+            })
+            .and_then(|(stream, ..)| aio::shutdown(stream));
+
+        current_thread::block_on_all(done).unwrap();
+    });
+
+    let mut fd = fs::File::open("Cargo.toml").unwrap();
+    let mut buf = HEADER.to_vec();
+    buf.resize(HEADER.len() + 22, 0);
+    fd.read_exact(&mut buf[HEADER.len()..]).unwrap();
+
+    let mut sock = net::TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+    let mut stream = StreamOwned::new(sess, sock);
 
-    let buf2 = current_thread::block_on_all(done).unwrap();
+    let mut buf2 = Vec::new();
+    stream.write_all(b"aaa").unwrap();
+    stream.read_to_end(&mut buf2).unwrap();
     assert_eq!(buf2, buf);
 }
+
+#[test]
+fn test_send_file_range_overflow() {
+    let fd = fs::File::open("Cargo.toml").unwrap();
+
+    let err = SendFileProgress::new(&fd, ..=u64::max_value()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let range = (Bound::Excluded(u64::max_value()), Bound::Unbounded);
+    let err = SendFileProgress::new(&fd, range).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    let err = SendFileProgress::new(&fd, 4..2).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    assert!(SendFileProgress::new(&fd, ..u64::max_value()).is_ok());
+    assert!(SendFileProgress::new(&fd, 2..2).is_ok());
+}
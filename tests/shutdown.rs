@@ -0,0 +1,177 @@
+mod common;
+
+use std::io::{ self, Read, Write };
+use std::time::Duration;
+use std::net::{ TcpStream, SocketAddr, Shutdown };
+use std::sync::mpsc::channel;
+use tokio::runtime::current_thread;
+use ktls::{ State, CloseStatus, KtlsErrorKind };
+use ktls::codec::{ Level, AlertDescription };
+use tokio_rusktls::KtlsStream;
+use self::common::{ connect, into_tokio, run_server };
+
+
+#[test]
+fn test_half_close() {
+    let addr = run_ktls_server(|mut stream| {
+        // the request ends when the client closes its write side
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        assert_eq!(stream.state(), State::ReadClosed);
+
+        let mut response = b"echo: ".to_vec();
+        response.extend_from_slice(&request);
+        stream.write_all(&response).unwrap();
+        stream.get_mut().shutdown(Shutdown::Both).unwrap();
+        assert_eq!(stream.state(), State::Closed);
+    });
+
+    let mut stream = connect_ktls(&addr);
+
+    stream.write_all(b"hello").unwrap();
+    stream.get_mut().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(stream.state(), State::WriteClosed);
+    assert!(stream.write(b"world").is_err());
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert_eq!(response, b"echo: hello");
+    assert_eq!(stream.state(), State::Closed);
+}
+
+fn run_ktls_server<F>(f: F) -> SocketAddr
+where F: FnOnce(KtlsStream<TcpStream>) + Send + 'static
+{
+    run_server(move |sock, sess| {
+        let stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        f(stream);
+    })
+}
+
+fn connect_ktls(addr: &SocketAddr) -> KtlsStream<TcpStream> {
+    let mut sock = TcpStream::connect(addr).unwrap();
+    let sess = connect(&mut sock);
+
+    KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap()
+}
+
+#[test]
+fn test_close_gracefully() {
+    let addr = run_ktls_server(|mut stream| {
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(b"unread reply").unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        stream.get_mut().shutdown(Shutdown::Both).unwrap();
+    });
+
+    let mut stream = connect_ktls(&addr);
+    stream.write_all(b"hello").unwrap();
+
+    let status = stream.get_mut()
+        .close_gracefully(Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(status, CloseStatus::Clean);
+    assert_eq!(stream.state(), State::Closed);
+}
+
+#[test]
+fn test_close_truncated() {
+    // drop the connection without close_notify
+    let addr = run_ktls_server(|stream| drop(stream.into_inner().into_inner()));
+
+    let mut stream = connect_ktls(&addr);
+    let status = stream.get_mut().close_gracefully(None).unwrap();
+    assert_eq!(status, CloseStatus::Truncated);
+}
+
+#[test]
+fn test_close_timeout() {
+    let (send, recv) = channel::<()>();
+
+    // keep the connection open without answering
+    let addr = run_ktls_server(move |_stream| {
+        let _ = recv.recv();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+
+    let stream = KtlsStream::new(into_tokio(sock), sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let done = stream.close_gracefully(Some(Duration::from_millis(200)));
+    let (_, status) = current_thread::block_on_all(done).unwrap();
+    assert_eq!(status, CloseStatus::Truncated);
+    send.send(()).unwrap();
+}
+
+#[test]
+fn test_shutdown_failed() {
+    let addr = run_ktls_server(|mut stream| {
+        stream.send_alert(Level::Fatal, AlertDescription::InternalError).unwrap();
+
+        // no close_notify on a failed stream, but TCP still gets its FIN
+        stream.get_mut().shutdown(Shutdown::Write).unwrap();
+        assert_eq!(
+            stream.state(),
+            State::Failed(KtlsErrorKind::AlertSent(AlertDescription::InternalError))
+        );
+    });
+
+    let mut stream = connect_ktls(&addr);
+    let mut buf = [0; 1];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(
+        KtlsErrorKind::of(&err),
+        Some(KtlsErrorKind::AlertReceived(AlertDescription::InternalError))
+    );
+
+    let sock = stream.get_mut().get_mut();
+    assert_eq!(sock.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_shutdown_would_block() {
+    let (send, recv) = channel::<()>();
+
+    let addr = run_ktls_server(move |mut stream| {
+        // read nothing until the client is stuck
+        recv.recv().unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(stream.state(), State::ReadClosed);
+    });
+
+    let mut stream = connect_ktls(&addr);
+    stream.get_mut().get_mut().set_nonblocking(true).unwrap();
+
+    let buf = [0; 4096];
+    loop {
+        match stream.write(&buf) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("{:?}", err)
+        }
+    }
+
+    // close_notify can not go out, so neither does the FIN
+    let err = stream.get_mut().shutdown(Shutdown::Write).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    assert_eq!(stream.state(), State::Open);
+
+    send.send(()).unwrap();
+    stream.get_mut().get_mut().set_nonblocking(false).unwrap();
+    stream.get_mut().shutdown(Shutdown::Write).unwrap();
+    assert_eq!(stream.state(), State::WriteClosed);
+}
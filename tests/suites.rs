@@ -0,0 +1,71 @@
+mod common;
+
+use std::io::{ Read, Write };
+use std::net::{ TcpStream, Shutdown };
+use std::sync::mpsc::channel;
+use rustls::SupportedCipherSuite;
+use rustls::crypto::ring::cipher_suite;
+use tokio_rusktls::KtlsStream;
+use self::common::{ get_server_config_with, connect, run_server_with };
+
+
+fn echo_with(suite: SupportedCipherSuite) {
+    let (send, recv) = channel();
+
+    let addr = run_server_with(get_server_config_with(suite), move |sock, sess| {
+        let mut stream = KtlsStream::new(sock, sess)
+            .map_err(|err| err.error)
+            .unwrap();
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.get_mut().shutdown(Shutdown::Write).unwrap();
+        send.send(()).unwrap();
+    });
+
+    let mut sock = TcpStream::connect(&addr).unwrap();
+    let sess = connect(&mut sock);
+    assert!(sess.negotiated_cipher_suite() == Some(suite));
+
+    let mut stream = KtlsStream::new(sock, sess)
+        .map_err(|err| err.error)
+        .unwrap();
+
+    let mut buf = Vec::new();
+    stream.write_all(b"hello").unwrap();
+    stream.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"hello");
+
+    recv.recv().unwrap();
+}
+
+#[test]
+fn test_tls12_aes_128_gcm() {
+    echo_with(cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+}
+
+#[test]
+fn test_tls12_aes_256_gcm() {
+    echo_with(cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
+}
+
+#[test]
+fn test_tls12_chacha20_poly1305() {
+    echo_with(cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256);
+}
+
+#[test]
+fn test_tls13_aes_128_gcm() {
+    echo_with(cipher_suite::TLS13_AES_128_GCM_SHA256);
+}
+
+#[test]
+fn test_tls13_aes_256_gcm() {
+    echo_with(cipher_suite::TLS13_AES_256_GCM_SHA384);
+}
+
+#[test]
+fn test_tls13_chacha20_poly1305() {
+    echo_with(cipher_suite::TLS13_CHACHA20_POLY1305_SHA256);
+}
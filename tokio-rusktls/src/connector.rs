@@ -0,0 +1,186 @@
+use std::{ io, mem };
+use std::sync::Arc;
+use std::os::unix::io::AsRawFd;
+use tokio::prelude::*;
+use rustls::{ ClientConfig, ServerConfig, ClientConnection, ServerConnection, Connection };
+use rustls::pki_types::ServerName;
+use ktls::rustls::{ RecordReader, ExporterLog };
+use crate::{ KtlsStream, MaybeKtls };
+
+
+/// Keying material to export at install, as `(label, context, len)`.
+type Exports = Arc<Vec<(Vec<u8>, Option<Vec<u8>>, usize)>>;
+
+/// Runs client handshakes with a rustls config and installs kTLS on the result.
+///
+/// The config needs `enable_secret_extraction` set.
+#[derive(Clone)]
+pub struct KtlsConnector {
+    config: Arc<ClientConfig>,
+    fallback: bool,
+    exports: Exports
+}
+
+/// Runs server handshakes with a rustls config and installs kTLS on the result.
+///
+/// The config needs `enable_secret_extraction` set.
+#[derive(Clone)]
+pub struct KtlsAcceptor {
+    config: Arc<ServerConfig>,
+    fallback: bool,
+    exports: Exports
+}
+
+impl From<Arc<ClientConfig>> for KtlsConnector {
+    fn from(config: Arc<ClientConfig>) -> KtlsConnector {
+        KtlsConnector { config, fallback: false, exports: Arc::new(Vec::new()) }
+    }
+}
+
+impl From<Arc<ServerConfig>> for KtlsAcceptor {
+    fn from(config: Arc<ServerConfig>) -> KtlsAcceptor {
+        KtlsAcceptor { config, fallback: false, exports: Arc::new(Vec::new()) }
+    }
+}
+
+impl KtlsConnector {
+    /// Keep the session on rustls when kTLS can not be installed, instead of failing.
+    ///
+    /// See `MaybeKtls::new` for which failures can fall back.
+    pub fn fallback(mut self, fallback: bool) -> KtlsConnector {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Export keying material for each `(label, context, len)` at install,
+    /// to be read back through `KtlsStream::export_keying_material`.
+    /// Only needed on TLS 1.2, TLS 1.3 streams derive any material on demand.
+    pub fn exports(mut self, exports: &[(&[u8], Option<&[u8]>, usize)]) -> KtlsConnector {
+        self.exports = owned(exports);
+        self
+    }
+
+    pub fn connect<IO>(&self, domain: ServerName<'static>, io: IO) -> Connect<IO>
+    where IO: AsyncRead + AsyncWrite + AsRawFd
+    {
+        let (config, log) = ExporterLog::client(&self.config);
+        let handshake = match ClientConnection::new(config, domain) {
+            Ok(session) => MidHandshake::Handshaking(io, session.into(), RecordReader::new(), self.fallback, self.exports.clone(), log),
+            Err(err) => MidHandshake::Failed(io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+
+        Connect(handshake)
+    }
+}
+
+impl KtlsAcceptor {
+    /// Keep the session on rustls when kTLS can not be installed, instead of failing.
+    ///
+    /// See `MaybeKtls::new` for which failures can fall back.
+    pub fn fallback(mut self, fallback: bool) -> KtlsAcceptor {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Export keying material for each `(label, context, len)` at install,
+    /// to be read back through `KtlsStream::export_keying_material`.
+    /// Only needed on TLS 1.2, TLS 1.3 streams derive any material on demand.
+    pub fn exports(mut self, exports: &[(&[u8], Option<&[u8]>, usize)]) -> KtlsAcceptor {
+        self.exports = owned(exports);
+        self
+    }
+
+    pub fn accept<IO>(&self, io: IO) -> Accept<IO>
+    where IO: AsyncRead + AsyncWrite + AsRawFd
+    {
+        let (config, log) = ExporterLog::server(&self.config);
+        let handshake = match ServerConnection::new(config) {
+            Ok(session) => MidHandshake::Handshaking(io, session.into(), RecordReader::new(), self.fallback, self.exports.clone(), log),
+            Err(err) => MidHandshake::Failed(io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+
+        Accept(handshake)
+    }
+}
+
+/// Future of `KtlsConnector::connect`.
+pub struct Connect<IO>(MidHandshake<IO>);
+
+/// Future of `KtlsAcceptor::accept`.
+pub struct Accept<IO>(MidHandshake<IO>);
+
+enum MidHandshake<IO> {
+    Handshaking(IO, Connection, RecordReader, bool, Exports, Arc<ExporterLog>),
+    Failed(io::Error),
+    End
+}
+
+impl<IO> Future for Connect<IO>
+where IO: AsyncRead + AsyncWrite + AsRawFd
+{
+    type Item = MaybeKtls<IO>;
+    type Error = io::Error;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+impl<IO> Future for Accept<IO>
+where IO: AsyncRead + AsyncWrite + AsRawFd
+{
+    type Item = MaybeKtls<IO>;
+    type Error = io::Error;
+
+    #[inline]
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+impl<IO> Future for MidHandshake<IO>
+where IO: AsyncRead + AsyncWrite + AsRawFd
+{
+    type Item = MaybeKtls<IO>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let MidHandshake::Handshaking(io, session, records, ..) = self {
+            match records.complete_handshake(session, io) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                result => result?
+            }
+        }
+
+        match mem::replace(self, MidHandshake::End) {
+            MidHandshake::Handshaking(io, session, _, fallback, exports, log) => {
+                let exports = exports.iter()
+                    .map(|(label, context, len)| (label.as_slice(), context.as_ref().map(Vec::as_slice), *len))
+                    .collect::<Vec<_>>();
+
+                let stream = if fallback {
+                    MaybeKtls::with_exports(io, session, &exports)
+                } else {
+                    KtlsStream::with_exports(io, session, &exports).map(MaybeKtls::Ktls)
+                };
+
+                let mut stream = stream.map_err(|err| err.error)?;
+                if let MaybeKtls::Ktls(ref mut stream) = stream {
+                    stream.inner.handshake_mut().set_exporter_log(&log);
+                }
+
+                Ok(Async::Ready(stream))
+            },
+            MidHandshake::Failed(err) => Err(err),
+            MidHandshake::End => panic!("polled handshake after completion")
+        }
+    }
+}
+
+fn owned(exports: &[(&[u8], Option<&[u8]>, usize)]) -> Exports {
+    let exports = exports.iter()
+        .map(|&(label, context, len)| (label.to_vec(), context.map(Vec::from), len))
+        .collect();
+    Arc::new(exports)
+}
@@ -1,36 +1,154 @@
-mod common;
+mod maybe;
+mod connector;
+mod ready;
 
+use std::fs::File;
 use std::io::{ self, Read, Write };
+use std::ops::{ Bound, RangeBounds };
+use std::time::{ Duration, Instant };
 use std::os::unix::io::{ AsRawFd, RawFd };
 use bytes::Buf;
 use tokio::prelude::*;
 use tokio::io::{ AsyncRead, AsyncWrite };
-use rustls::Session;
-use if_chain::if_chain;
-use ktls::{ KtlsStream as InnerStream, Tls12CryptoInfoAesGcm128 };
-use crate::common::{ Rustls, IsClient };
+use tokio::timer::Delay;
+use rustls::{ Connection, ProtocolVersion, SupportedCipherSuite };
+use rustls::pki_types::CertificateDer;
+use ktls::{
+    KtlsStream as InnerStream, SendFileProgress,
+    State, CloseStatus, Renegotiation, SessionTicket
+};
+use ktls::codec::{ Level, AlertDescription, ContentType };
+use ktls::rustls::{ Rustls, Handshake, KtlsStream as SyncStream };
+use crate::ready::Readiness;
 
+pub use ktls::rustls::ChannelBinding;
+pub use crate::maybe::{ MaybeKtls, RustlsStream };
+pub use crate::connector::{ KtlsConnector, KtlsAcceptor, Connect, Accept };
 
+
+/// `ktls::rustls::KtlsStream` driven by the tokio reactor.
 #[derive(Debug)]
 pub struct KtlsStream<IO> {
-    io: InnerStream<IO, Rustls>,
-    is_shutdown: bool
+    inner: SyncStream<IO>,
+    ready: Readiness
+}
+
+impl<IO> From<SyncStream<IO>> for KtlsStream<IO> {
+    fn from(inner: SyncStream<IO>) -> KtlsStream<IO> {
+        KtlsStream { inner, ready: Readiness::new() }
+    }
 }
 
 impl<IO> KtlsStream<IO> {
     #[inline]
     pub fn get_ref(&self) -> &InnerStream<IO, Rustls> {
-        &self.io
+        self.inner.get_ref()
     }
 
     #[inline]
     pub fn get_mut(&mut self) -> &mut InnerStream<IO, Rustls> {
-        &mut self.io
+        self.inner.get_mut()
     }
 
     #[inline]
     pub fn into_inner(self) -> InnerStream<IO, Rustls> {
-        self.io
+        self.inner.into_inner()
+    }
+
+    /// The blocking stream this wraps.
+    #[inline]
+    pub fn into_sync(self) -> SyncStream<IO> {
+        self.inner
+    }
+
+    /// What the handshake negotiated.
+    #[inline]
+    pub fn handshake(&self) -> &Handshake {
+        self.inner.handshake()
+    }
+
+    /// The negotiated protocol version.
+    #[inline]
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.inner.protocol_version()
+    }
+
+    /// The negotiated cipher suite.
+    #[inline]
+    pub fn cipher_suite(&self) -> SupportedCipherSuite {
+        self.inner.cipher_suite()
+    }
+
+    /// The protocol agreed on through ALPN, if any.
+    #[inline]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.inner.alpn_protocol()
+    }
+
+    /// The certificate chain the peer presented, leaf first.
+    #[inline]
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.inner.peer_certificates()
+    }
+
+    /// The SNI the client sent. Only known on the server side.
+    #[inline]
+    pub fn server_name(&self) -> Option<&str> {
+        self.inner.server_name()
+    }
+
+    /// Data the client sent as TLS 1.3 0-RTT, which can be replayed.
+    ///
+    /// It is only given out here, reads start with what was sent after the handshake.
+    #[inline]
+    pub fn early_data(&self) -> Option<&[u8]> {
+        self.inner.early_data()
+    }
+
+    /// Keying material as in RFC 5705 and RFC 8446 section 7.5.
+    ///
+    /// On TLS 1.3 streams from `KtlsConnector` and `KtlsAcceptor`, any material can be
+    /// derived. Otherwise only material asked for at install is available, through
+    /// `with_exports` or `exports` on `KtlsConnector` and `KtlsAcceptor`.
+    #[inline]
+    pub fn export_keying_material(&self, label: &[u8], context: Option<&[u8]>, len: usize)
+        -> io::Result<Vec<u8>>
+    {
+        self.inner.export_keying_material(label, context, len)
+    }
+
+    /// Set the certificate the server sent, for `tls-server-end-point` on the server side.
+    #[inline]
+    pub fn set_server_certificate(&mut self, cert: CertificateDer<'static>) {
+        self.inner.set_server_certificate(cert)
+    }
+
+    /// Channel binding data of the connection, for SASL `-PLUS` mechanisms.
+    #[inline]
+    pub fn channel_binding(&self, kind: ChannelBinding) -> io::Result<Vec<u8>> {
+        self.inner.channel_binding(kind)
+    }
+
+    #[inline]
+    pub fn last_alert(&self) -> Option<(Level, AlertDescription)> {
+        self.inner.last_alert()
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.inner.state()
+    }
+
+    #[inline]
+    pub fn set_renegotiation(&mut self, renegotiation: Renegotiation) {
+        self.inner.set_renegotiation(renegotiation)
+    }
+
+    #[inline]
+    pub fn set_ticket_handler<F>(&mut self, f: F)
+    where F: FnMut(SessionTicket) + Send + 'static
+    {
+        self.inner.set_ticket_handler(f)
     }
 }
 
@@ -38,45 +156,192 @@ impl<IO> KtlsStream<IO>
 where
     IO: Read + Write + AsRawFd,
 {
-    pub fn new<S>(io: IO, session: &S)
-        -> Result<Self, ktls::Error<IO>>
-        where S: Session + IsClient
+    /// Install kTLS on `io` with the keys of a finished `session`.
+    ///
+    /// See `ktls::rustls::install` for what happens to the session.
+    pub fn new<S>(io: IO, session: S)
+        -> Result<Self, ktls::Error<(IO, Option<Connection>)>>
+        where S: Into<Connection>
     {
-        if session.is_handshaking() {
-            return Err(ktls::Error {
-                error: io::Error::new(io::ErrorKind::Other, "handshake is not completed."),
-                inner: io
-            });
-        }
+        KtlsStream::with_exports(io, session, &[])
+    }
+
+    /// Like `new`, and also export keying material for each `(label, context, len)`
+    /// from the session, to be read back through `export_keying_material`.
+    pub fn with_exports<S>(io: IO, session: S, exports: &[(&[u8], Option<&[u8]>, usize)])
+        -> Result<Self, ktls::Error<(IO, Option<Connection>)>>
+        where S: Into<Connection>
+    {
+        SyncStream::with_exports(io, session, exports).map(KtlsStream::from)
+    }
+}
+
+impl<IO: AsRawFd> KtlsStream<IO> {
+    pub fn send_close_notify(&mut self) -> io::Result<()> {
+        self.inner.get_mut().send_close_notify()
+    }
+
+    #[inline]
+    pub fn send_alert(&mut self, level: Level, desc: AlertDescription) -> io::Result<()> {
+        self.inner.get_mut().send_alert(level, desc)
+    }
 
-        if_chain! {
-            if let Some(rustls::ProtocolVersion::TLSv1_2) = session.get_protocol_version();
-            if let Some(scs) = session.get_negotiated_ciphersuite();
-            if rustls::BulkAlgorithm::AES_128_GCM == scs.bulk;
-            if let Some(secrets) = session.get_secrets();
-            then {
-                let key_block = secrets.make_key_block(scs.key_block_len());
-                let (tx, rx) = Tls12CryptoInfoAesGcm128::from_secrets(
-                    <S as IsClient>::FLAG,
-                    &key_block,
-                    session.get_seq()
-                );
-
-                let kstream =  InnerStream::new(io, &tx, &rx)?;
-                Ok(KtlsStream { io: kstream, is_shutdown: false })
-            } else {
-                Err(ktls::Error {
-                    error: io::Error::new(io::ErrorKind::Other, "protocol/algorithm is not supported."),
-                    inner: io
-                })
+    #[inline]
+    pub fn send_record(&mut self, typ: ContentType, buf: &[u8]) -> io::Result<()> {
+        self.inner.get_mut().send_record(typ, buf)
+    }
+
+    #[inline]
+    pub fn write_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.get_mut().write_record(buf)
+    }
+
+    #[inline]
+    pub fn read_record(&mut self, buf: &mut [u8]) -> io::Result<(ContentType, usize)> {
+        self.inner.get_mut().read_record(buf)
+    }
+
+    /// Send `header` followed by `range` of `file`, with the header sharing a record
+    /// with the first bytes of the file.
+    pub fn send_with_file<H, B>(self, header: H, file: File, range: B) -> SendWithFile<IO, H>
+    where
+        H: AsRef<[u8]>,
+        B: RangeBounds<u64>
+    {
+        fn cloned(bound: Bound<&u64>) -> Bound<u64> {
+            match bound {
+                Bound::Included(&n) => Bound::Included(n),
+                Bound::Excluded(&n) => Bound::Excluded(n),
+                Bound::Unbounded => Bound::Unbounded
             }
         }
+
+        SendWithFile {
+            inner: Some((self, header, file)),
+            range: (cloned(range.start_bound()), cloned(range.end_bound())),
+            progress: None
+        }
     }
 }
 
 impl<IO: AsRawFd> KtlsStream<IO> {
-    pub fn send_close_notify(&mut self) -> io::Result<()> {
-        self.io.send_close_notify()
+    /// Run `f`, a write that goes around the reactor, until it no longer blocks.
+    fn poll_write_with<T, F>(&mut self, mut f: F) -> Poll<T, io::Error>
+    where F: FnMut(&mut InnerStream<IO, Rustls>) -> io::Result<T>
+    {
+        loop {
+            match f(self.inner.get_mut()) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result.map(Async::Ready)
+            }
+
+            if let Async::NotReady = self.ready.poll_write(&self.inner)? {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+pub struct SendWithFile<IO, H> {
+    inner: Option<(KtlsStream<IO>, H, File)>,
+    range: (Bound<u64>, Bound<u64>),
+    progress: Option<SendFileProgress>
+}
+
+impl<IO, H> Future for SendWithFile<IO, H>
+where
+    IO: AsRawFd,
+    H: AsRef<[u8]>
+{
+    type Item = (KtlsStream<IO>, H, File);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let (stream, header, file) = self.inner.as_mut()
+                .expect("polled SendWithFile after completion");
+
+            let progress = match self.progress {
+                Some(ref mut progress) => progress,
+                None => self.progress.get_or_insert(SendFileProgress::new(file, self.range)?)
+            };
+
+            let sent = stream.poll_write_with(|io| io.resume_send_with_file(progress, header.as_ref(), file))?;
+            if let Async::NotReady = sent {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        Ok(Async::Ready(self.inner.take().unwrap()))
+    }
+}
+
+impl<IO> KtlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsRawFd,
+{
+    /// Send close_notify, then discard incoming data until the peer's close_notify.
+    ///
+    /// The close is `Truncated` if the connection ends without it, or if `timeout`
+    /// passes first.
+    pub fn close_gracefully(self, timeout: Option<Duration>) -> CloseGracefully<IO> {
+        CloseGracefully {
+            inner: Some(self),
+            delay: timeout.map(|timeout| Delay::new(Instant::now() + timeout))
+        }
+    }
+}
+
+pub struct CloseGracefully<IO> {
+    inner: Option<KtlsStream<IO>>,
+    delay: Option<Delay>
+}
+
+impl<IO> Future for CloseGracefully<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsRawFd,
+{
+    type Item = (KtlsStream<IO>, CloseStatus);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let status = {
+            let stream = self.inner.as_mut()
+                .expect("polled CloseGracefully after completion");
+            let mut buf = [0; 1024];
+
+            if stream.state().can_write() {
+                if let Async::NotReady = stream.poll_write_with(|io| io.send_close_notify())? {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            loop {
+                match stream.state() {
+                    State::Failed(kind) => return Err(kind.into_error()),
+                    state if !state.can_read() => break CloseStatus::Clean,
+                    _ => ()
+                }
+
+                match stream.poll_read(&mut buf) {
+                    Ok(Async::Ready(0)) if stream.state().can_read() => break CloseStatus::Truncated,
+                    Ok(Async::Ready(_)) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset =>
+                        break CloseStatus::Truncated,
+                    Err(err) => return Err(err),
+                    Ok(Async::NotReady) => match self.delay {
+                        Some(ref mut delay) => match delay.poll() {
+                            Ok(Async::Ready(())) => break CloseStatus::Truncated,
+                            Ok(Async::NotReady) => return Ok(Async::NotReady),
+                            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err))
+                        },
+                        None => return Ok(Async::NotReady)
+                    }
+                }
+            }
+        };
+
+        Ok(Async::Ready((self.inner.take().unwrap(), status)))
     }
 }
 
@@ -85,19 +350,29 @@ where
     IO: Read + Write + AsRawFd,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.io.read(buf)
+        loop {
+            match self.inner.read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && task::is_in_task() => (),
+                result => return result
+            }
+
+            // recvmsg goes around the reactor, so wait on it here before trying again.
+            if let Async::NotReady = self.ready.poll_read(&self.inner)? {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
     }
 }
 
 impl<IO: Write> Write for KtlsStream<IO> {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.io.write(buf)
+        self.inner.write(buf)
     }
 
     #[inline]
     fn flush(&mut self) -> io::Result<()> {
-        self.io.flush()
+        self.inner.flush()
     }
 }
 
@@ -115,33 +390,24 @@ where
     IO: AsyncRead + AsyncWrite + AsRawFd,
 {
     fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
-        self.io.get_mut().write_buf(buf)
+        self.inner.state().check_write()?;
+        self.inner.get_mut().get_mut().write_buf(buf)
     }
 
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        macro_rules! try_async {
-            ( $op:expr ) => {
-                match $op {
-                    Ok(n) => n,
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
-                        return Ok(Async::NotReady),
-                    Err(e) => return Err(e)
-                }
+        if self.inner.state().can_write() {
+            if let Async::NotReady = self.poll_write_with(|io| io.send_close_notify())? {
+                return Ok(Async::NotReady);
             }
         }
 
-        if !self.is_shutdown {
-            try_async!(self.send_close_notify());
-            self.is_shutdown = true;
-        }
-
-        self.io.get_mut().shutdown()
+        self.inner.get_mut().get_mut().shutdown()
     }
 }
 
 impl<IO: AsRawFd> AsRawFd for KtlsStream<IO> {
     #[inline]
     fn as_raw_fd(&self) -> RawFd {
-        self.io.as_raw_fd()
+        self.inner.as_raw_fd()
     }
 }
@@ -0,0 +1,290 @@
+use std::mem;
+use std::io::{ self, Read, Write };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use bytes::Buf;
+use tokio::prelude::*;
+use tokio::io::{ AsyncRead, AsyncWrite };
+use rustls::Connection;
+use ktls::KtlsErrorKind;
+use ktls::rustls::kernel_supports;
+use crate::KtlsStream;
+
+
+/// A TLS stream on kTLS where it can be installed, and on rustls otherwise.
+#[derive(Debug)]
+pub enum MaybeKtls<IO> {
+    Ktls(KtlsStream<IO>),
+    Rustls(RustlsStream<IO>)
+}
+
+impl<IO> MaybeKtls<IO>
+where
+    IO: Read + Write + AsRawFd,
+{
+    /// Install kTLS on `io` if it can be, and keep the session in userspace if not.
+    ///
+    /// This falls back to rustls when the kernel can not take the keys: the suite is not
+    /// one kTLS has or the running kernel rejects it, or the `tls` ULP can not be attached.
+    /// The suite is tried on a loopback connection of its own the first time, see
+    /// `ktls::probe`, and if that connection fails, as with `EMFILE`, this falls back too.
+    /// It also does when rustls holds part of a record. Other failures, as `HandshakeIncomplete` or `PendingWrite`,
+    /// are errors that hand the session back, to retry later.
+    pub fn new<S>(io: IO, session: S)
+        -> Result<Self, ktls::Error<(IO, Option<Connection>)>>
+        where S: Into<Connection>
+    {
+        MaybeKtls::with_exports(io, session, &[])
+    }
+
+    /// Like `new`, and also export keying material for each `(label, context, len)`
+    /// when kTLS is installed. On rustls, the session can still export it.
+    pub fn with_exports<S>(mut io: IO, session: S, exports: &[(&[u8], Option<&[u8]>, usize)])
+        -> Result<Self, ktls::Error<(IO, Option<Connection>)>>
+        where S: Into<Connection>
+    {
+        let session = session.into();
+
+        if session.is_handshaking() {
+            let error = KtlsErrorKind::HandshakeIncomplete.into_error();
+            return Err(ktls::Error { error, inner: (io, Some(session)) });
+        }
+
+        let checked = match kernel_supports(&session) {
+            Ok(true) => ktls::attach_ulp(&mut io),
+            Ok(false) => Err(KtlsErrorKind::Unsupported.into_error()),
+            // the probe could not be run, as with `EMFILE`, so kTLS is not tried
+            Err(reason) => return Ok(MaybeKtls::Rustls(RustlsStream { io, session, reason, pending: 0 }))
+        };
+
+        let (io, session, reason) = match checked {
+            Ok(()) => match KtlsStream::with_exports(io, session, exports) {
+                Ok(stream) => return Ok(MaybeKtls::Ktls(stream)),
+                Err(ktls::Error { error, inner: (io, Some(session)) }) if falls_back(&error) =>
+                    (io, session, error),
+                Err(err) => return Err(err)
+            },
+            Err(error) => if falls_back(&error) {
+                (io, session, error)
+            } else {
+                return Err(ktls::Error { error, inner: (io, Some(session)) });
+            }
+        };
+
+        Ok(MaybeKtls::Rustls(RustlsStream { io, session, reason, pending: 0 }))
+    }
+}
+
+/// Whether kTLS can not be installed for the session at all, rather than not yet.
+fn falls_back(err: &io::Error) -> bool {
+    match KtlsErrorKind::of(err) {
+        Some(KtlsErrorKind::Unsupported)
+            | Some(KtlsErrorKind::UlpNotLoaded)
+            | Some(KtlsErrorKind::PartialRecord) => true,
+        _ => false
+    }
+}
+
+impl<IO> MaybeKtls<IO> {
+    #[inline]
+    pub fn is_ktls(&self) -> bool {
+        match self {
+            MaybeKtls::Ktls(_) => true,
+            MaybeKtls::Rustls(_) => false
+        }
+    }
+
+    /// Why kTLS was not installed, if it was not.
+    #[inline]
+    pub fn fallback_reason(&self) -> Option<&io::Error> {
+        match self {
+            MaybeKtls::Ktls(_) => None,
+            MaybeKtls::Rustls(stream) => Some(stream.reason())
+        }
+    }
+}
+
+/// A rustls session on `io`, for when kTLS could not be installed.
+#[derive(Debug)]
+pub struct RustlsStream<IO> {
+    io: IO,
+    session: Connection,
+    reason: io::Error,
+    /// Plaintext rustls took in a `write` that did not return yet, since its records
+    /// were still to be written out.
+    pending: usize
+}
+
+impl<IO> RustlsStream<IO> {
+    #[inline]
+    pub fn get_ref(&self) -> (&IO, &Connection) {
+        (&self.io, &self.session)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> (&mut IO, &mut Connection) {
+        (&mut self.io, &mut self.session)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> (IO, Connection) {
+        (self.io, self.session)
+    }
+
+    /// Why kTLS was not installed.
+    #[inline]
+    pub fn reason(&self) -> &io::Error {
+        &self.reason
+    }
+}
+
+impl<IO: Write> RustlsStream<IO> {
+    /// Write out what rustls has queued, as far as `io` takes it.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.io)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<IO: Read + Write> Read for RustlsStream<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result
+            }
+
+            // handshake messages and alerts rustls queued while reading
+            self.write_tls()?;
+
+            self.session.read_tls(&mut self.io)?;
+
+            if let Err(err) = self.session.process_new_packets() {
+                // best effort to tell the peer
+                let _ = self.write_tls();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+}
+
+impl<IO: Write> Write for RustlsStream<IO> {
+    /// A write only returns once its records are written out, so nothing is left
+    /// queued in rustls waiting for a `flush` that may not come. If `io` would block
+    /// on them, this fails with `WouldBlock`, and the retry with the same `buf`
+    /// reports the bytes rustls already took.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending == 0 {
+            self.write_tls()?;
+            self.pending = self.session.writer().write(buf)?;
+        }
+
+        self.write_tls()?;
+        Ok(mem::replace(&mut self.pending, 0))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.writer().flush()?;
+        self.write_tls()?;
+        self.io.flush()
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> AsyncRead for RustlsStream<IO> {
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
+        false
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> AsyncWrite for RustlsStream<IO> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.session.send_close_notify();
+
+        match self.write_tls() {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            result => result?
+        }
+
+        self.io.shutdown()
+    }
+}
+
+impl<IO: AsRawFd> AsRawFd for RustlsStream<IO> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+impl<IO> Read for MaybeKtls<IO>
+where
+    IO: Read + Write + AsRawFd,
+{
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MaybeKtls::Ktls(stream) => stream.read(buf),
+            MaybeKtls::Rustls(stream) => stream.read(buf)
+        }
+    }
+}
+
+impl<IO: Write> Write for MaybeKtls<IO> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeKtls::Ktls(stream) => stream.write(buf),
+            MaybeKtls::Rustls(stream) => stream.write(buf)
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeKtls::Ktls(stream) => stream.flush(),
+            MaybeKtls::Rustls(stream) => stream.flush()
+        }
+    }
+}
+
+impl<IO> AsyncRead for MaybeKtls<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsRawFd,
+{
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
+        false
+    }
+}
+
+impl<IO> AsyncWrite for MaybeKtls<IO>
+where
+    IO: AsyncRead + AsyncWrite + AsRawFd,
+{
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            MaybeKtls::Ktls(stream) => stream.write_buf(buf),
+            MaybeKtls::Rustls(stream) => stream.write_buf(buf)
+        }
+    }
+
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            MaybeKtls::Ktls(stream) => AsyncWrite::shutdown(stream),
+            MaybeKtls::Rustls(stream) => AsyncWrite::shutdown(stream)
+        }
+    }
+}
+
+impl<IO: AsRawFd> AsRawFd for MaybeKtls<IO> {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MaybeKtls::Ktls(stream) => stream.as_raw_fd(),
+            MaybeKtls::Rustls(stream) => stream.as_raw_fd()
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::io;
+use std::os::unix::io::{ AsRawFd, RawFd };
+use mio::unix::EventedFd;
+use tokio::prelude::*;
+use tokio::reactor::Registration;
+
+
+/// Reactor interest for the syscalls kTLS makes around the inner io.
+///
+/// Control records and sendfile do not go through `AsyncRead`/`AsyncWrite` of the
+/// inner io, so the readiness tokio caches for it is never cleared by them. A
+/// duplicate of the fd gets a registration of its own instead, which reports each
+/// readiness event once.
+#[derive(Debug)]
+pub(crate) struct Readiness(Option<(RawFd, Registration)>);
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness(None)
+    }
+
+    fn registration<Fd: AsRawFd>(&mut self, io: &Fd) -> io::Result<&Registration> {
+        if self.0.is_none() {
+            let fd = unsafe { libc::fcntl(io.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // registered after the syscall that would block, but epoll reports
+            // readiness that is already there, so nothing is missed.
+            let registration = Registration::new();
+            if let Err(err) = registration.register(&EventedFd(&fd)) {
+                unsafe { libc::close(fd) };
+                return Err(err);
+            }
+
+            self.0 = Some((fd, registration));
+        }
+
+        Ok(&self.0.as_ref().unwrap().1)
+    }
+
+    /// Wait for `io` to become readable again, after a read of it would block.
+    pub fn poll_read<Fd: AsRawFd>(&mut self, io: &Fd) -> Poll<(), io::Error> {
+        self.registration(io)?.poll_read_ready().map(|ready| ready.map(drop))
+    }
+
+    /// Wait for `io` to become writable again, after a write to it would block.
+    pub fn poll_write<Fd: AsRawFd>(&mut self, io: &Fd) -> Poll<(), io::Error> {
+        self.registration(io)?.poll_write_ready().map(|ready| ready.map(drop))
+    }
+}
+
+impl Drop for Readiness {
+    fn drop(&mut self) {
+        if let Some((fd, mut registration)) = self.0.take() {
+            // epoll keeps watching the socket until every fd of it is closed
+            let _ = registration.deregister(&EventedFd(&fd));
+            unsafe { libc::close(fd) };
+        }
+    }
+}